@@ -0,0 +1,129 @@
+//! Compares concurrent lookups in `TtlCache` with the single `RwLock<HashMap>` it replaced, where
+//! every lookup also took the write lock to check whether it was time to prune.
+//!
+//! Run with `cargo bench --bench cache`.
+
+// its tests are compiled without the test harness here, so their imports go unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/cache.rs"]
+mod cache;
+#[allow(dead_code)]
+#[path = "../src/constants.rs"]
+mod constants;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use cache::{CacheOptions, TtlCache};
+
+const KEYS: usize = 1_000;
+const GETS_PER_THREAD: usize = 200_000;
+const THREADS: &[usize] = &[1, 2, 4, 8];
+const MAX_OPS_BEFORE_PRUNE: u8 = 16;
+
+/// The cache as it was before it was sharded.
+struct LockedCache {
+    expiry: Duration,
+    users: RwLock<HashMap<String, (String, Instant)>>,
+    elapsed_ops: AtomicU8,
+}
+
+impl LockedCache {
+    fn new(expiry: Duration) -> Self {
+        LockedCache {
+            expiry,
+            users: RwLock::new(HashMap::new()),
+            elapsed_ops: AtomicU8::new(0),
+        }
+    }
+
+    fn insert(&self, key: &str, value: String) {
+        let mut users = self.users.write().unwrap();
+        users.insert(String::from(key), (value, Instant::now()));
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let computed = {
+            let users = self.users.read().unwrap();
+            let (value, created) = users.get(key)?;
+            if created.elapsed() > self.expiry {
+                None
+            } else {
+                Some(value.clone())
+            }
+        };
+        self.prune_old_entries(computed.is_none());
+        computed
+    }
+
+    fn prune_old_entries(&self, force: bool) {
+        let mut users = self.users.write().unwrap();
+        self.elapsed_ops.fetch_add(1, Ordering::Relaxed);
+        if self.elapsed_ops.load(Ordering::Relaxed) >= MAX_OPS_BEFORE_PRUNE || force {
+            let now = Instant::now();
+            users.retain(|_, (_, created)| now.saturating_duration_since(*created) < self.expiry);
+            self.elapsed_ops.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Runs `GETS_PER_THREAD` lookups on each of `threads` threads and returns the lookups per
+/// second across all of them.
+fn run<F>(threads: usize, get: Arc<F>) -> f64
+where F: Fn(&str) -> Option<String> + Send + Sync + 'static
+{
+    let keys: Arc<Vec<String>> = Arc::new((0..KEYS).map(|i| format!("key-{}", i)).collect());
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let get = get.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                for i in 0..GETS_PER_THREAD {
+                    let key = &keys[(i * 7 + t * 13) % KEYS];
+                    assert!(get(key).is_some());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    (threads * GETS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let expiry = Duration::new(60 * 60, 0);
+    let sharded = Arc::new(TtlCache::with_options(
+        expiry,
+        CacheOptions {
+            max_entries: Some(KEYS * 2),
+            ..CacheOptions::default()
+        },
+    ));
+    let locked = Arc::new(LockedCache::new(expiry));
+    for i in 0..KEYS {
+        let key = format!("key-{}", i);
+        sharded.insert(&key, key.clone());
+        locked.insert(&key, key.clone());
+    }
+
+    println!("{:>8} {:>16} {:>16}", "threads", "RwLock<HashMap>", "TtlCache");
+    for &threads in THREADS {
+        let locked = locked.clone();
+        let sharded = sharded.clone();
+        let locked_rate = run(threads, Arc::new(move |key: &str| locked.get(key)));
+        let sharded_rate = run(threads, Arc::new(move |key: &str| sharded.get(key)));
+        println!(
+            "{:>8} {:>14.0}/s {:>14.0}/s",
+            threads, locked_rate, sharded_rate
+        );
+    }
+}
@@ -1,29 +1,62 @@
-use atomic_counter::{AtomicCounter, RelaxedCounter};
-use bcrypt::BcryptError;
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::{Status, Cookie},
+    http::{Cookie, Cookies, Method, SameSite, Status},
     request::{FromRequest, Request, State},
     response::Response,
     Outcome,
 };
-use serde::{Deserialize, Serialize};
-use std::sync::{PoisonError, RwLock};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use ring::{
+    constant_time, digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    ops::Deref,
+    path::Path,
+    sync::{atomic::AtomicU32, Arc, Mutex, PoisonError},
+};
 use uuid::{adapter::Hyphenated, Uuid};
 
-use crate::{cache::TtlCache, constants};
+use crate::{
+    cache::TtlCache,
+    constants,
+    password::{PasswordError, PasswordHasher},
+    session::{ClientInfo, SessionStore},
+    totp,
+};
 
 pub fn generate_session_token() -> String {
     format!("{}", Hyphenated::from(Uuid::new_v4()))
 }
 
+/// Hashes a random token for storage. The tokens are long enough that a fast hash is fine.
+pub fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
 #[derive(Debug)]
 pub enum AuthenticationError {
     UsernameTaken,
     UserNotFound,
     IncorrectPassword,
-    HashError(BcryptError),
+    InvalidToken,
+    InvalidCode,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnabled,
+    AccountDisabled,
+    RegistrationClosed,
+    InviteRequired,
+    InvalidInvite,
+    /// The user is the only admin who can still log in, so they can't be disabled or deleted.
+    LastAdmin,
+    HashError(PasswordError),
+    /// Users in the legacy store that couldn't be moved into the user database, with why.
+    MigrationConflict(Vec<String>),
     StoreInaccessible,
 }
 
@@ -39,8 +72,14 @@ impl From<pickledb::error::Error> for AuthenticationError {
     }
 }
 
-impl From<BcryptError> for AuthenticationError {
-    fn from(error: BcryptError) -> Self {
+impl From<rusqlite::Error> for AuthenticationError {
+    fn from(_error: rusqlite::Error) -> Self {
+        Self::StoreInaccessible
+    }
+}
+
+impl From<PasswordError> for AuthenticationError {
+    fn from(error: PasswordError) -> Self {
         Self::HashError(error)
     }
 }
@@ -52,81 +91,927 @@ pub struct User {
     pub id: u64,
     pub name: String,
     pub password: String,
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Set when an admin resets the password; the user has to pick a new one to log in.
+    #[serde(default)]
+    pub must_change_password: bool,
 }
 
+impl User {
+    pub fn has_two_factor(&self) -> bool {
+        self.two_factor.as_ref().map_or(false, |t| t.enabled)
+    }
+}
+
+/// A user's TOTP settings. The secret is set aside when enrolment starts, and only takes effect
+/// once the user proves their authenticator has it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    secret: String,
+    enabled: bool,
+    /// The last time step a code was accepted for; codes for it or earlier steps are refused.
+    last_step: u64,
+    /// Hashes of the unused recovery codes.
+    recovery_codes: Vec<String>,
+}
+
+/// What a personal API token is allowed to do. Sessions can always do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenScope {
+    Read,
+    ReadWrite,
+}
+
+impl TokenScope {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(TokenScope::Read),
+            "read-write" => Some(TokenScope::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// A personal API token, as stored with its user. Only the hash of the secret is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    /// Left empty when the token is shown to its user.
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    hash: String,
+}
+
+/// Who may register. The first user can always register, since otherwise there would be nobody
+/// to invite anyone.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+    Open,
+    /// Registering needs an invite code from an existing user.
+    Invite,
+    Closed,
+}
+
+impl RegistrationMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "open" => Some(RegistrationMode::Open),
+            "invite" => Some(RegistrationMode::Invite),
+            "closed" => Some(RegistrationMode::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// A single-use invite code, as shown to the user who made it. Only the hash of the code is
+/// kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invite {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+/// Users, kept in SQLite. Usernames are unique once lowercased, and ids come from an
+/// `AUTOINCREMENT` sequence, so a deleted user's id is never handed out again.
 pub struct AuthStore {
-    db: RwLock<PickleDb>,
-    id_counter: RelaxedCounter,
+    db: Mutex<Connection>,
+    /// The user named as admin in the config, if any. Otherwise the first user to register is
+    /// the admin.
+    admin_name: Option<String>,
+    registration: RegistrationMode,
+    hasher: PasswordHasher,
 }
 
+/// Changes to the schema, in order. The database's `user_version` is the number applied so far.
+const SCHEMA_MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        norm_name TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        password TEXT NOT NULL,
+        api_tokens TEXT NOT NULL DEFAULT '[]',
+        two_factor TEXT
+    );",
+    "ALTER TABLE users ADD COLUMN is_admin INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE users ADD COLUMN must_change_password INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE invites (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL UNIQUE,
+        created_by INTEGER NOT NULL,
+        created INTEGER NOT NULL,
+        expires INTEGER NOT NULL
+    );",
+];
+/// The migration that added the admin role.
+const ADMIN_MIGRATION: i64 = 2;
+const USER_COLUMNS: &str =
+    "id, name, password, api_tokens, two_factor, is_admin, disabled, must_change_password";
+
 impl AuthStore {
-    pub fn new(db_path: &str) -> Self {
-        let db = PickleDb::load(
-            db_path,
-            PickleDbDumpPolicy::AutoDump,
-            SerializationMethod::Json,
-        );
+    /// Opens the user database, first moving over any users from the JSON file at `legacy_path`
+    /// that they used to be kept in.
+    pub fn new(
+        db_path: &str,
+        legacy_path: &str,
+        admin_name: Option<String>,
+        registration: RegistrationMode,
+        hasher: PasswordHasher,
+    ) -> Result<Self, AuthenticationError> {
+        let mut db = Connection::open(db_path)?;
+        let version: i64 = db.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        migrate_schema(&mut db, SCHEMA_MIGRATIONS)?;
+        migrate_legacy_store(&mut db, legacy_path)?;
 
-        let db = match db {
-            Ok(db) => db,
-            Err(_) => PickleDb::new(
-                db_path,
-                PickleDbDumpPolicy::AutoDump,
-                SerializationMethod::Json,
-            ),
-        };
+        let admin_name = admin_name.map(|name| name.to_lowercase());
+        match &admin_name {
+            Some(name) => {
+                db.execute(
+                    "UPDATE users SET is_admin = 1 WHERE norm_name = ?1",
+                    params![name],
+                )?;
+            }
+            // users from before there were admins, given one the way a new install would be:
+            // only once, as the column is added, and never again once there's an admin to lose
+            None if version < ADMIN_MIGRATION => {
+                db.execute(
+                    "UPDATE users SET is_admin = 1 WHERE id = (SELECT MIN(id) FROM users)",
+                    params![],
+                )?;
+            }
+            None => {}
+        }
 
-        let next_id = db.total_keys();
+        Ok(AuthStore {
+            db: Mutex::new(db),
+            admin_name,
+            registration,
+            hasher,
+        })
+    }
 
-        AuthStore {
-            db: RwLock::new(db),
-            id_counter: RelaxedCounter::new(next_id),
-        }
+    pub fn registration_mode(&self) -> RegistrationMode {
+        self.registration
     }
 
-    pub fn register_user(&self, name: &str, password: &str) -> Result<(), AuthenticationError> {
+    /// Registers a new user. In invite mode, `invite` has to be an unused, unexpired code, and
+    /// it's used up along with creating the user.
+    pub fn register_user(
+        &self,
+        name: &str,
+        password: &str,
+        invite: Option<&str>,
+    ) -> Result<(), AuthenticationError> {
+        use AuthenticationError::*;
         let norm_name = name.to_lowercase();
+        // checked before hashing, so that refused registrations don't cost a hash, and again
+        // after, since the invite could have been used in the meantime
+        self.admit(&*self.db.lock()?, invite, false)?;
+        let password = self.hasher.hash(password)?;
 
-        let mut db = self.db.write()?;
-        let existing_user: Option<User> = db.get(&name);
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let has_users = self.admit(&tx, invite, true)?;
+        let is_admin = match &self.admin_name {
+            Some(admin_name) => *admin_name == norm_name,
+            None => !has_users,
+        };
+        let result = tx.execute(
+            "INSERT INTO users (norm_name, name, password, is_admin) VALUES (?1, ?2, ?3, ?4)",
+            params![norm_name, name, password, is_admin],
+        );
+        match result.and_then(|_| tx.commit()) {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(UsernameTaken)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        if let Some(_) = existing_user {
-            return Err(AuthenticationError::UsernameTaken);
+    /// Checks that someone may register under the registration mode, using up their invite if
+    /// `use_invite` is set. Returns whether there are users already, since the first one may
+    /// always register.
+    fn admit(
+        &self,
+        db: &Connection,
+        invite: Option<&str>,
+        use_invite: bool,
+    ) -> Result<bool, AuthenticationError> {
+        use AuthenticationError::*;
+        let has_users: bool =
+            db.query_row("SELECT EXISTS (SELECT 1 FROM users)", params![], |row| {
+                row.get(0)
+            })?;
+        if has_users {
+            match self.registration {
+                RegistrationMode::Open => {}
+                RegistrationMode::Closed => return Err(RegistrationClosed),
+                RegistrationMode::Invite => {
+                    let code = invite.filter(|c| !c.is_empty()).ok_or(InviteRequired)?;
+                    let params = params![hash_token(code), Utc::now().timestamp()];
+                    let valid = if use_invite {
+                        db.execute(
+                            "DELETE FROM invites WHERE hash = ?1 AND expires > ?2",
+                            params,
+                        )? > 0
+                    } else {
+                        db.query_row(
+                            "SELECT EXISTS (SELECT 1 FROM invites WHERE hash = ?1 AND expires > ?2)",
+                            params,
+                            |row| row.get(0),
+                        )?
+                    };
+                    if !valid {
+                        return Err(InvalidInvite);
+                    }
+                }
+            }
         }
+        Ok(has_users)
+    }
+
+    /// Creates an invite code, returning its details and the code itself.
+    pub fn create_invite(
+        &self,
+        user_id: u64,
+        lifetime: Duration,
+    ) -> Result<(Invite, String), AuthenticationError> {
+        let mut secret = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| AuthenticationError::StoreInaccessible)?;
+        let code = base64::encode_config(&secret, base64::URL_SAFE_NO_PAD);
 
-        let id = self.id_counter.inc() as u64;
-        let password = bcrypt::hash(&password, constants::BCRYPT_ITERATIONS)?;
-        db.set(
-            &norm_name,
-            &User {
-                id,
-                name: String::from(name),
-                password: String::from(password),
-            },
+        // stored to the second, so it's shown that way from the start
+        let now = Utc.timestamp(Utc::now().timestamp(), 0);
+        let invite = Invite {
+            id: generate_session_token(),
+            created: now,
+            expires: now + lifetime,
+        };
+
+        let db = self.db.lock()?;
+        db.execute(
+            "DELETE FROM invites WHERE expires <= ?1",
+            params![now.timestamp()],
         )?;
-        Ok(())
+        db.execute(
+            "INSERT INTO invites (id, hash, created_by, created, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                invite.id,
+                hash_token(&code),
+                user_id as i64,
+                invite.created.timestamp(),
+                invite.expires.timestamp(),
+            ],
+        )?;
+        Ok((invite, code))
+    }
+
+    /// Lists the unused invites a user has made.
+    pub fn list_invites(&self, user_id: u64) -> Result<Vec<Invite>, AuthenticationError> {
+        let db = self.db.lock()?;
+        let mut statement = db.prepare(
+            "SELECT id, created, expires FROM invites
+             WHERE created_by = ?1 AND expires > ?2 ORDER BY created",
+        )?;
+        let invites = statement
+            .query_map(params![user_id as i64, Utc::now().timestamp()], |row| {
+                Ok(Invite {
+                    id: row.get(0)?,
+                    created: Utc.timestamp(row.get(1)?, 0),
+                    expires: Utc.timestamp(row.get(2)?, 0),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Invite>>>()?;
+        Ok(invites)
+    }
+
+    /// Withdraws one of a user's invites. Returns whether the user had such an invite.
+    pub fn revoke_invite(&self, user_id: u64, id: &str) -> Result<bool, AuthenticationError> {
+        let db = self.db.lock()?;
+        let deleted = db.execute(
+            "DELETE FROM invites WHERE id = ?1 AND created_by = ?2",
+            params![id, user_id as i64],
+        )?;
+        Ok(deleted > 0)
     }
 
+    /// Checks a user's password. A hash made with an old algorithm or cost is replaced while the
+    /// password is at hand.
     pub fn authenticate_user(
         &self,
         name: &str,
         password: &str,
     ) -> Result<User, AuthenticationError> {
+        // the lock isn't held while hashing
+        let mut stored_user = self.get_user(name)?;
+
+        if !self.hasher.verify(password, &stored_user.password)? {
+            return Err(AuthenticationError::IncorrectPassword);
+        }
+        if stored_user.disabled {
+            return Err(AuthenticationError::AccountDisabled);
+        }
+
+        if self.hasher.needs_rehash(&stored_user.password) {
+            let old_hash = stored_user.password.clone();
+            let rehashed = self.hasher.hash(password).map_err(From::from).and_then(|hash| {
+                self.update_user(name, |user| {
+                    // unless the password was changed in the meantime
+                    if user.password == old_hash {
+                        user.password = hash;
+                    }
+                    Ok(user.password.clone())
+                })
+            });
+            match rehashed {
+                Ok(hash) => stored_user.password = hash,
+                Err(e) => println!("Could not rehash the password for {}: {:?}", name, e),
+            }
+        }
+        Ok(stored_user)
+    }
+
+    pub fn change_password(
+        &self,
+        name: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), AuthenticationError> {
+        self.authenticate_user(name, old_password)?;
+        self.set_password(name, new_password)
+    }
+
+    /// Replaces a user's password, clearing any reset an admin asked for.
+    pub fn set_password(&self, name: &str, password: &str) -> Result<(), AuthenticationError> {
+        let hash = self.hash_password(password)?;
+        self.set_password_hash(name, hash)
+    }
+
+    /// Hashes a password the way the store would, for a password that has to be kept a while
+    /// before it's set.
+    pub fn hash_password(&self, password: &str) -> Result<String, AuthenticationError> {
+        Ok(self.hasher.hash(password)?)
+    }
+
+    /// Like `set_password`, with a hash from `hash_password`.
+    pub fn set_password_hash(&self, name: &str, hash: String) -> Result<(), AuthenticationError> {
+        self.update_user(name, |user| {
+            user.password = hash;
+            user.must_change_password = false;
+            Ok(())
+        })
+    }
+
+    pub fn list_users(&self) -> Result<Vec<User>, AuthenticationError> {
+        let db = self.db.lock()?;
+        let mut statement = db.prepare(&format!("SELECT {} FROM users ORDER BY id", USER_COLUMNS))?;
+        let users = statement
+            .query_map(params![], user_from_row)?
+            .collect::<rusqlite::Result<Vec<User>>>()?;
+        Ok(users)
+    }
+
+    pub fn get_user_by_id(&self, id: u64) -> Result<User, AuthenticationError> {
+        let db = self.db.lock()?;
+        db.query_row(
+            &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+            params![id as i64],
+            user_from_row,
+        )
+        .optional()?
+        .ok_or(AuthenticationError::UserNotFound)
+    }
+
+    pub fn is_admin(&self, id: u64) -> bool {
+        self.get_user_by_id(id).map_or(false, |user| user.is_admin && !user.disabled)
+    }
+
+    /// Whether the user is the only admin who can still log in.
+    pub fn is_last_admin(&self, id: u64) -> Result<bool, AuthenticationError> {
+        let db = self.db.lock()?;
+        let user = db
+            .query_row(
+                &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+                params![id as i64],
+                user_from_row,
+            )
+            .optional()?
+            .ok_or(AuthenticationError::UserNotFound)?;
+        Ok(is_last_admin(&db, &user)?)
+    }
+
+    pub fn set_disabled(&self, name: &str, disabled: bool) -> Result<(), AuthenticationError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let mut user =
+            find_user(&tx, &name.to_lowercase())?.ok_or(AuthenticationError::UserNotFound)?;
+        if disabled && is_last_admin(&tx, &user)? {
+            return Err(AuthenticationError::LastAdmin);
+        }
+        user.disabled = disabled;
+        save_user(&tx, &user)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Gives a user a random temporary password, returning it. They have to choose a new one
+    /// the next time they log in.
+    pub fn reset_password(&self, name: &str) -> Result<String, AuthenticationError> {
+        let mut bytes = [0u8; 12];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| AuthenticationError::StoreInaccessible)?;
+        let temporary = base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+
+        let password = self.hasher.hash(&temporary)?;
+        self.update_user(name, |user| {
+            user.password = password;
+            user.must_change_password = true;
+            Ok(())
+        })?;
+        Ok(temporary)
+    }
+
+    pub fn delete_user(&self, name: &str) -> Result<(), AuthenticationError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let user = find_user(&tx, &name.to_lowercase())?.ok_or(AuthenticationError::UserNotFound)?;
+        if is_last_admin(&tx, &user)? {
+            return Err(AuthenticationError::LastAdmin);
+        }
+        tx.execute(
+            "DELETE FROM invites WHERE created_by = ?1",
+            params![user.id as i64],
+        )?;
+        tx.execute("DELETE FROM users WHERE id = ?1", params![user.id as i64])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Starts TOTP enrolment, returning the new secret. Any earlier unfinished enrolment is
+    /// replaced.
+    pub fn begin_two_factor(&self, name: &str) -> Result<String, AuthenticationError> {
+        self.update_user(name, |user| {
+            if user.has_two_factor() {
+                return Err(AuthenticationError::TwoFactorAlreadyEnabled);
+            }
+
+            let secret = totp::generate_secret().ok_or(AuthenticationError::StoreInaccessible)?;
+            user.two_factor = Some(TwoFactor {
+                secret: secret.clone(),
+                enabled: false,
+                last_step: 0,
+                recovery_codes: vec![],
+            });
+            Ok(secret)
+        })
+    }
+
+    /// Finishes TOTP enrolment with a code from the authenticator, returning the recovery codes.
+    pub fn confirm_two_factor(
+        &self,
+        name: &str,
+        code: &str,
+    ) -> Result<Vec<String>, AuthenticationError> {
         use AuthenticationError::*;
+        self.update_user(name, |user| {
+            let two_factor = match user.two_factor.as_mut() {
+                Some(t) if t.enabled => return Err(TwoFactorAlreadyEnabled),
+                Some(t) => t,
+                None => return Err(TwoFactorNotEnabled),
+            };
+
+            let step = totp::verify(&two_factor.secret, code, Utc::now().timestamp())
+                .ok_or(InvalidCode)?;
+            let codes = (0..constants::RECOVERY_CODE_COUNT)
+                .map(|_| totp::generate_recovery_code())
+                .collect::<Option<Vec<String>>>()
+                .ok_or(StoreInaccessible)?;
+
+            two_factor.enabled = true;
+            two_factor.last_step = step;
+            two_factor.recovery_codes = codes
+                .iter()
+                .map(|c| hash_token(&totp::normalize_recovery_code(c)))
+                .collect();
+            Ok(codes)
+        })
+    }
+
+    /// Turns TOTP off, given a current code or a recovery code.
+    pub fn disable_two_factor(&self, name: &str, code: &str) -> Result<(), AuthenticationError> {
+        self.update_user(name, |user| {
+            check_second_factor(user, code)?;
+            user.two_factor = None;
+            Ok(())
+        })
+    }
+
+    /// The second step of logging in, for users with TOTP turned on. A recovery code is used up
+    /// when it is accepted.
+    pub fn authenticate_second_factor(
+        &self,
+        name: &str,
+        code: &str,
+    ) -> Result<User, AuthenticationError> {
+        self.update_user(name, |user| {
+            if user.disabled {
+                return Err(AuthenticationError::AccountDisabled);
+            }
+            check_second_factor(user, code)?;
+            Ok(user.clone())
+        })
+    }
+
+    /// Creates a personal API token for a user, returning its details and the token itself. The
+    /// token can't be recovered later. It names its user, so it can be checked without searching
+    /// every user's tokens.
+    pub fn create_api_token(
+        &self,
+        name: &str,
+        token_name: &str,
+        scope: TokenScope,
+        lifetime: Duration,
+    ) -> Result<(ApiToken, String), AuthenticationError> {
         let norm_name = name.to_lowercase();
 
-        let db = self.db.read()?;
-        let stored_user: User = match db.get(&norm_name) {
-            None => return Err(UserNotFound),
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| AuthenticationError::StoreInaccessible)?;
+        let token = format!(
+            "{}.{}",
+            base64::encode_config(&norm_name, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&secret, base64::URL_SAFE_NO_PAD)
+        );
+
+        let now = Utc::now();
+        let api_token = ApiToken {
+            id: generate_session_token(),
+            name: String::from(token_name),
+            scope,
+            created: now,
+            expires: now + lifetime,
+            hash: hash_token(&token),
+        };
+
+        self.update_user(name, |user| {
+            user.api_tokens.retain(|t| t.expires > now);
+            user.api_tokens.push(api_token.clone());
+            Ok(())
+        })?;
+
+        let api_token = ApiToken {
+            hash: String::new(),
+            ..api_token
+        };
+        Ok((api_token, token))
+    }
+
+    pub fn list_api_tokens(&self, name: &str) -> Result<Vec<ApiToken>, AuthenticationError> {
+        let user = self.get_user(name)?;
+        let now = Utc::now();
+        Ok(user
+            .api_tokens
+            .into_iter()
+            .filter(|t| t.expires > now)
+            .map(|t| ApiToken {
+                hash: String::new(),
+                ..t
+            })
+            .collect())
+    }
+
+    /// Revokes one of a user's API tokens. Returns whether the user had such a token.
+    pub fn revoke_api_token(&self, name: &str, id: &str) -> Result<bool, AuthenticationError> {
+        self.update_user(name, |user| {
+            let count = user.api_tokens.len();
+            user.api_tokens.retain(|t| t.id != id);
+            Ok(user.api_tokens.len() != count)
+        })
+    }
+
+    /// Finds the user an API token belongs to, and what the token allows.
+    pub fn authenticate_api_token(
+        &self,
+        token: &str,
+    ) -> Result<(User, TokenScope), AuthenticationError> {
+        use AuthenticationError::*;
+
+        let encoded_name = token.split('.').next().ok_or(InvalidToken)?;
+        let norm_name = base64::decode_config(encoded_name, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|name| String::from_utf8(name).ok())
+            .ok_or(InvalidToken)?;
+
+        let user = self.get_user(&norm_name).map_err(|_| InvalidToken)?;
+        if user.disabled {
+            return Err(AccountDisabled);
+        }
+        let hash = hash_token(token);
+        let now = Utc::now();
+        let scope = user
+            .api_tokens
+            .iter()
+            .find(|t| t.hash == hash && t.expires > now)
+            .map(|t| t.scope)
+            .ok_or(InvalidToken)?;
+        Ok((user, scope))
+    }
+
+    fn get_user(&self, name: &str) -> Result<User, AuthenticationError> {
+        let db = self.db.lock()?;
+        find_user(&db, &name.to_lowercase())?.ok_or(AuthenticationError::UserNotFound)
+    }
+
+    /// Loads a user, lets `change` modify it and saves it, in one transaction. Nothing is saved
+    /// if `change` fails.
+    fn update_user<F, T>(&self, name: &str, change: F) -> Result<T, AuthenticationError>
+    where
+        F: FnOnce(&mut User) -> Result<T, AuthenticationError>,
+    {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let mut user =
+            find_user(&tx, &name.to_lowercase())?.ok_or(AuthenticationError::UserNotFound)?;
+        let result = change(&mut user)?;
+        save_user(&tx, &user)?;
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+fn find_user(db: &Connection, norm_name: &str) -> rusqlite::Result<Option<User>> {
+    db.query_row(
+        &format!("SELECT {} FROM users WHERE norm_name = ?1", USER_COLUMNS),
+        params![norm_name],
+        user_from_row,
+    )
+    .optional()
+}
+
+/// Whether `user` is the only admin who can still log in. Disabling or deleting them would
+/// leave nobody to manage users.
+fn is_last_admin(db: &Connection, user: &User) -> rusqlite::Result<bool> {
+    if !user.is_admin || user.disabled {
+        return Ok(false);
+    }
+    let other_admins: bool = db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE is_admin = 1 AND disabled = 0 AND id != ?1)",
+        params![user.id as i64],
+        |row| row.get(0),
+    )?;
+    Ok(!other_admins)
+}
+
+fn save_user(db: &Connection, user: &User) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE users SET name = ?1, password = ?2, api_tokens = ?3, two_factor = ?4,
+         is_admin = ?5, disabled = ?6, must_change_password = ?7 WHERE id = ?8",
+        params![
+            user.name,
+            user.password,
+            to_json(&user.api_tokens)?,
+            user.two_factor.as_ref().map(to_json).transpose()?,
+            user.is_admin,
+            user.disabled,
+            user.must_change_password,
+            user.id as i64,
+        ],
+    )?;
+    Ok(())
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let api_tokens: String = row.get(3)?;
+    let two_factor: Option<String> = row.get(4)?;
+    Ok(User {
+        id: row.get::<_, i64>(0)? as u64,
+        name: row.get(1)?,
+        password: row.get(2)?,
+        api_tokens: from_json(3, &api_tokens)?,
+        two_factor: two_factor.map(|t| from_json(4, &t)).transpose()?,
+        is_admin: row.get(5)?,
+        disabled: row.get(6)?,
+        must_change_password: row.get(7)?,
+    })
+}
+
+pub fn to_json<T: Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+pub fn from_json<T: DeserializeOwned>(column: usize, json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e)))
+}
+
+/// Applies the migrations the database doesn't have yet, going by its `user_version`.
+pub fn migrate_schema(db: &mut Connection, migrations: &[&str]) -> rusqlite::Result<()> {
+    let version: i64 = db.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    let tx = db.transaction()?;
+    for migration in migrations.iter().skip(version as usize) {
+        tx.execute_batch(migration)?;
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {}", migrations.len()))?;
+    tx.commit()
+}
+
+/// Copies users out of the PickleDb JSON file they used to be kept in, keeping their ids. The
+/// file is renamed once they're all in, so this only happens once. Notes belong to user ids, so
+/// a user whose id or name is already taken can't be given another; instead nothing is copied,
+/// and the users in the way are listed in the error.
+fn migrate_legacy_store(db: &mut Connection, legacy_path: &str) -> Result<(), AuthenticationError> {
+    if !Path::new(legacy_path).exists() {
+        return Ok(());
+    }
+    let legacy = PickleDb::load(
+        legacy_path,
+        PickleDbDumpPolicy::NeverDump,
+        SerializationMethod::Json,
+    )?;
+
+    let tx = db.transaction()?;
+    let mut count = 0;
+    let mut conflicts = Vec::new();
+    for item in legacy.iter() {
+        let user: User = match item.get_value() {
             Some(user) => user,
+            None => {
+                conflicts.push(format!("{}: could not be read", item.get_key()));
+                continue;
+            }
         };
+        let norm_name = item.get_key().to_lowercase();
+        let taken_by: Vec<(i64, String)> = tx
+            .prepare("SELECT id, norm_name FROM users WHERE id = ?1 OR norm_name = ?2")?
+            .query_map(params![user.id as i64, norm_name], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        match taken_by.as_slice() {
+            [] => {}
+            // already copied, by an earlier run that couldn't rename the file afterwards
+            [(id, name)] if *id as u64 == user.id && *name == norm_name => continue,
+            _ => {
+                let others: Vec<String> = taken_by
+                    .into_iter()
+                    .map(|(id, name)| format!("{} (id {})", name, id))
+                    .collect();
+                conflicts.push(format!(
+                    "{} (id {}): clashes with {}",
+                    user.name,
+                    user.id,
+                    others.join(" and ")
+                ));
+                continue;
+            }
+        }
+        count += tx.execute(
+            "INSERT INTO users (id, norm_name, name, password, api_tokens, two_factor)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user.id as i64,
+                norm_name,
+                user.name,
+                user.password,
+                to_json(&user.api_tokens)?,
+                user.two_factor.as_ref().map(to_json).transpose()?,
+            ],
+        )?;
+    }
+    if !conflicts.is_empty() {
+        // dropping the transaction rolls back the users that did go in
+        return Err(AuthenticationError::MigrationConflict(conflicts));
+    }
+    tx.commit()?;
 
-        if bcrypt::verify(password, &stored_user.password)? {
-            Ok(stored_user)
-        } else {
-            Err(IncorrectPassword)
+    fs::rename(
+        legacy_path,
+        format!("{}{}", legacy_path, constants::MIGRATED_SUFFIX),
+    )
+    .map_err(|_| AuthenticationError::StoreInaccessible)?;
+    println!("Moved {} users from {} into the user database", count, legacy_path);
+    Ok(())
+}
+
+/// Checks a TOTP or recovery code, recording its use on `user`.
+fn check_second_factor(user: &mut User, code: &str) -> Result<(), AuthenticationError> {
+    use AuthenticationError::*;
+    let two_factor = match user.two_factor.as_mut() {
+        Some(t) if t.enabled => t,
+        _ => return Err(TwoFactorNotEnabled),
+    };
+
+    if let Some(step) = totp::verify(&two_factor.secret, code, Utc::now().timestamp()) {
+        if step <= two_factor.last_step {
+            return Err(InvalidCode);
         }
+        two_factor.last_step = step;
+        return Ok(());
     }
+
+    let hash = hash_token(&totp::normalize_recovery_code(code));
+    match two_factor.recovery_codes.iter().position(|c| *c == hash) {
+        Some(i) => {
+            two_factor.recovery_codes.remove(i);
+            Ok(())
+        }
+        None => Err(InvalidCode),
+    }
+}
+
+/// The CSRF token for a session. It's derived from the session token, so it changes along with
+/// it and doesn't have to be stored.
+pub fn csrf_token(session_token: &str) -> String {
+    hash_token(&format!("csrf:{}", session_token))
+}
+
+/// How the session cookies are sent, from the config.
+#[derive(Debug, Clone, Copy)]
+pub struct CookieSettings {
+    /// Whether the cookies are only sent over HTTPS.
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl CookieSettings {
+    pub fn parse_same_site(name: &str) -> Option<SameSite> {
+        match name {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+
+    /// The session cookie, which scripts can't read, and the CSRF cookie that goes with it,
+    /// which the client reads and sends back in a header.
+    pub fn session_cookies(self, token: &str) -> Vec<Cookie<'static>> {
+        vec![
+            Cookie::build(constants::SESSION_COOKIE_NAME, String::from(token))
+                .path("/")
+                .http_only(true)
+                .secure(self.secure)
+                .same_site(self.same_site)
+                .finish(),
+            Cookie::build(constants::CSRF_COOKIE_NAME, csrf_token(token))
+                .path("/")
+                .secure(self.secure)
+                .same_site(self.same_site)
+                .finish(),
+        ]
+    }
+
+    pub fn remove_session_cookies(self, cookies: &mut Cookies) {
+        for name in &[constants::SESSION_COOKIE_NAME, constants::CSRF_COOKIE_NAME] {
+            cookies.remove(Cookie::build(*name, "").path("/").finish());
+        }
+    }
+}
+
+/// Whether a request could change something, and so needs a CSRF token when it's made with a
+/// session cookie.
+fn is_unsafe_method(method: Method) -> bool {
+    match method {
+        Method::Get | Method::Head | Method::Options => false,
+        _ => true,
+    }
+}
+
+/// Users who have passed the password step of logging in but still owe a TOTP code, by the
+/// challenge token they were given.
+pub struct PendingLogins(pub TtlCache<PendingLogin>);
+
+#[derive(Clone)]
+pub struct PendingLogin {
+    pub name: String,
+    /// The hash of the password to set once the second factor is given, when an admin reset the
+    /// old one. Only the hash is kept, so the password itself isn't held in memory for as long as
+    /// the challenge lasts.
+    pub new_password_hash: Option<String>,
+    /// Wrong codes given for the challenge so far. Copies of the entry share it, so attempts
+    /// made at the same time are all counted.
+    pub failures: Arc<AtomicU32>,
 }
 
 #[derive(Debug, Clone)]
@@ -134,12 +1019,25 @@ pub struct AuthenticatedUser {
     pub id: u64,
     pub name: String,
     pub token: String,
+    pub session_id: String,
+    /// Set when the request was made with an API token rather than a session.
+    pub scope: Option<TokenScope>,
+}
+
+impl AuthenticatedUser {
+    pub fn can_write(&self) -> bool {
+        self.scope.map_or(true, |scope| scope == TokenScope::ReadWrite)
+    }
 }
 
 #[derive(Debug)]
 pub enum AuthTokenError {
     MissingToken,
     InvalidToken,
+    InsufficientScope,
+    SessionRequired,
+    AdminRequired,
+    CsrfMismatch,
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
@@ -148,20 +1046,132 @@ impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
     fn from_request(request: &'a Request<'r>) -> Outcome<Self, (Status, Self::Error), ()> {
         use AuthTokenError::*;
 
+        if let Some(header) = request.headers().get_one("Authorization") {
+            let token = match header.splitn(2, ' ').collect::<Vec<_>>().as_slice() {
+                [scheme, token] if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+                _ => return Outcome::Failure((Status::Unauthorized, InvalidToken)),
+            };
+            let auth_store = request
+                .guard::<State<AuthStore>>()
+                .expect("auth store not initialized");
+
+            return match auth_store.authenticate_api_token(token) {
+                Ok((user, scope)) => Outcome::Success(AuthenticatedUser {
+                    id: user.id,
+                    name: user.name,
+                    token: String::from(token),
+                    session_id: String::new(),
+                    scope: Some(scope),
+                }),
+                Err(_) => Outcome::Failure((Status::Unauthorized, InvalidToken)),
+            };
+        }
+
         let cookies = request.cookies();
         let session_cookie = cookies.get(constants::SESSION_COOKIE_NAME);
         if let Some(cookie_value) = session_cookie {
-            let user_store = request
-                .guard::<State<TtlCache<AuthenticatedUser>>>()
-                .expect("user store not initialized");
+            let sessions = request
+                .guard::<State<SessionStore>>()
+                .expect("session store not initialized");
+
+            let client = request.guard::<ClientInfo>().unwrap();
+
+            // requests with a bearer token are exempt, since a browser won't add one by itself
+            if is_unsafe_method(request.method()) {
+                let expected = csrf_token(cookie_value.value());
+                let sent = request.headers().get_one(constants::CSRF_HEADER_NAME);
+                let matches = sent.map_or(false, |sent| {
+                    constant_time::verify_slices_are_equal(sent.as_bytes(), expected.as_bytes())
+                        .is_ok()
+                });
+                if !matches {
+                    return Outcome::Failure((Status::Forbidden, CsrfMismatch));
+                }
+            }
 
-            if let Some(user) = user_store.get(cookie_value.value()) {
-                return Outcome::Success(user.clone());
+            if let Some(user) = sessions.get(cookie_value.value(), &client) {
+                Outcome::Success(user)
             } else {
-                return Outcome::Failure((Status::Unauthorized, InvalidToken));
+                Outcome::Failure((Status::Unauthorized, InvalidToken))
             }
         } else {
-            return Outcome::Failure((Status::Unauthorized, MissingToken));
+            Outcome::Failure((Status::Unauthorized, MissingToken))
+        }
+    }
+}
+
+/// A user who may change notes: one logged in with a session, or with a read-write API token.
+pub struct WritingUser(pub AuthenticatedUser);
+
+impl Deref for WritingUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for WritingUser {
+    type Error = AuthTokenError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let user = request.guard::<AuthenticatedUser>()?;
+        if user.can_write() {
+            Outcome::Success(WritingUser(user))
+        } else {
+            Outcome::Failure((Status::Forbidden, AuthTokenError::InsufficientScope))
+        }
+    }
+}
+
+/// A user logged in with a session. Managing the account itself can't be done with an API token.
+pub struct SessionUser(pub AuthenticatedUser);
+
+impl Deref for SessionUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SessionUser {
+    type Error = AuthTokenError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let user = request.guard::<AuthenticatedUser>()?;
+        if user.scope.is_none() {
+            Outcome::Success(SessionUser(user))
+        } else {
+            Outcome::Failure((Status::Forbidden, AuthTokenError::SessionRequired))
+        }
+    }
+}
+
+/// An admin, logged in with a session. Whether they're an admin is checked on every request,
+/// so that taking it away works straight away.
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl Deref for AdminUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminUser {
+    type Error = AuthTokenError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, (Status, Self::Error), ()> {
+        let user = request.guard::<SessionUser>()?;
+        let auth_store = request
+            .guard::<State<AuthStore>>()
+            .expect("auth store not initialized");
+        if auth_store.is_admin(user.id) {
+            Outcome::Success(AdminUser(user.0))
+        } else {
+            Outcome::Failure((Status::Forbidden, AuthTokenError::AdminRequired))
         }
     }
 }
@@ -180,19 +1190,258 @@ impl Fairing for TokenRefreshFairing {
         let cookies = request.cookies();
         let session_cookie = cookies.get(constants::SESSION_COOKIE_NAME);
         if let Some(cookie) = session_cookie {
-            let user_store = request
-                .guard::<State<TtlCache<AuthenticatedUser>>>()
-                .expect("user store not initialized");
-            if let Some((user, created)) = user_store.get_with_time(cookie.value()) {
-                if created.elapsed() > user_store.get_expiry() / 2 {
-                    let new_token = generate_session_token();
-                    let auth_cookie = Cookie::build(constants::SESSION_COOKIE_NAME, new_token.clone())
-                        .path("/")
-                        .finish();
-                    response.set_header(auth_cookie);
-                    user_store.insert(&new_token, user.clone());
+            let sessions = request
+                .guard::<State<SessionStore>>()
+                .expect("session store not initialized");
+            if let Some(new_token) = sessions.refresh(cookie.value()) {
+                let settings = request
+                    .guard::<State<CookieSettings>>()
+                    .expect("cookie settings not initialized");
+                for cookie in settings.session_cookies(&new_token) {
+                    response.adjoin_header(cookie);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    struct Paths {
+        dir: std::path::PathBuf,
+        db: String,
+        legacy: String,
+    }
+
+    impl Paths {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("soash-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = |name: &str| String::from(dir.join(name).to_str().unwrap());
+            Paths {
+                db: path("users.db"),
+                legacy: path("auth.db"),
+                dir,
+            }
+        }
+
+        /// Writes users to the PickleDb file, the way they used to be kept.
+        fn write_legacy(&self, users: &[(&str, Value)]) {
+            let mut legacy = PickleDb::load_json(&self.legacy, PickleDbDumpPolicy::AutoDump)
+                .unwrap_or_else(|_| {
+                    PickleDb::new(
+                        &self.legacy,
+                        PickleDbDumpPolicy::AutoDump,
+                        SerializationMethod::Json,
+                    )
+                });
+            for (key, user) in users {
+                legacy.set(key, user).unwrap();
+            }
+        }
+
+        fn open(&self) -> Result<AuthStore, AuthenticationError> {
+            self.open_with(PasswordHasher::new(8, 1, 1))
+        }
+
+        fn open_with(&self, hasher: PasswordHasher) -> Result<AuthStore, AuthenticationError> {
+            AuthStore::new(&self.db, &self.legacy, None, RegistrationMode::Open, hasher)
+        }
+
+        fn password_hash(&self, norm_name: &str) -> String {
+            let db = Connection::open(&self.db).unwrap();
+            db.query_row(
+                "SELECT password FROM users WHERE norm_name = ?",
+                params![norm_name],
+                |row| row.get(0),
+            )
+            .unwrap()
+        }
+
+        fn set_password_hash(&self, norm_name: &str, hash: &str) {
+            let db = Connection::open(&self.db).unwrap();
+            db.execute(
+                "UPDATE users SET password = ? WHERE norm_name = ?",
+                params![hash, norm_name],
+            )
+            .unwrap();
+        }
+
+        fn user_names(&self) -> Vec<String> {
+            self.names_where("1")
+        }
+
+        fn admin_names(&self) -> Vec<String> {
+            self.names_where("is_admin = 1")
+        }
+
+        fn names_where(&self, condition: &str) -> Vec<String> {
+            let db = Connection::open(&self.db).unwrap();
+            let query = format!(
+                "SELECT norm_name FROM users WHERE {} ORDER BY id",
+                condition
+            );
+            let mut statement = db.prepare(&query).unwrap();
+            let mut names = Vec::new();
+            for name in statement.query_map(params![], |row| row.get(0)).unwrap() {
+                names.push(name.unwrap());
+            }
+            names
+        }
+    }
+
+    impl Drop for Paths {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn legacy_user(id: u64, name: &str) -> Value {
+        json!({"id": id, "name": name, "password": "hash"})
+    }
+
+    fn assert_refused(paths: &Paths) {
+        match paths.open() {
+            Err(AuthenticationError::MigrationConflict(users)) => assert_eq!(users.len(), 1),
+            other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
+        }
+        assert!(paths.user_names().is_empty());
+        assert!(Path::new(&paths.legacy).exists());
+    }
+
+    #[test]
+    fn migration_moves_nobody_if_an_id_is_taken_twice() {
+        let paths = Paths::new();
+        paths.write_legacy(&[
+            ("alice", legacy_user(0, "alice")),
+            ("bob", legacy_user(0, "bob")),
+            ("carol", legacy_user(2, "carol")),
+        ]);
+        assert_refused(&paths);
+
+        // once the file is fixed, everyone is moved over
+        paths.write_legacy(&[("bob", legacy_user(1, "bob"))]);
+        paths.open().unwrap();
+        assert_eq!(paths.user_names(), vec!["alice", "bob", "carol"]);
+        assert!(!Path::new(&paths.legacy).exists());
+    }
+
+    #[test]
+    fn migration_moves_nobody_if_a_name_is_taken_twice() {
+        let paths = Paths::new();
+        paths.write_legacy(&[
+            ("alice", legacy_user(0, "alice")),
+            ("ALICE", legacy_user(1, "ALICE")),
+        ]);
+        assert_refused(&paths);
+    }
+
+    #[test]
+    fn migration_can_run_again_after_the_rename_failed() {
+        let paths = Paths::new();
+        paths.write_legacy(&[
+            ("alice", legacy_user(0, "alice")),
+            ("bob", legacy_user(1, "bob")),
+        ]);
+        paths.open().unwrap();
+
+        // as if the file couldn't be renamed the first time
+        fs::rename(
+            format!("{}{}", paths.legacy, constants::MIGRATED_SUFFIX),
+            &paths.legacy,
+        )
+        .unwrap();
+        paths.open().unwrap();
+        assert_eq!(paths.user_names(), vec!["alice", "bob"]);
+        assert!(!Path::new(&paths.legacy).exists());
+    }
+
+    #[test]
+    fn only_the_first_user_to_register_is_made_admin() {
+        let paths = Paths::new();
+        let store = paths.open().unwrap();
+        store.register_user("alice", "pw", None).unwrap();
+        store.register_user("bob", "pw", None).unwrap();
+        assert_eq!(paths.admin_names(), vec!["alice"]);
+
+        // as if the only admin had gone, which the store refuses to do itself
+        let db = Connection::open(&paths.db).unwrap();
+        db.execute("UPDATE users SET is_admin = 0", params![])
+            .unwrap();
+        store.register_user("carol", "pw", None).unwrap();
+        drop(store);
+        paths.open().unwrap();
+        assert!(paths.admin_names().is_empty());
+    }
+
+    #[test]
+    fn the_last_admin_cannot_be_disabled_or_deleted() {
+        let paths = Paths::new();
+        let store = paths.open().unwrap();
+        store.register_user("alice", "pw", None).unwrap();
+        store.register_user("bob", "pw", None).unwrap();
+        let last_admin = |result| match result {
+            Err(AuthenticationError::LastAdmin) => {}
+            other => panic!("expected LastAdmin, got {:?}", other),
+        };
+        last_admin(store.set_disabled("alice", true));
+        last_admin(store.delete_user("alice"));
+
+        // with another admin, either can go
+        let db = Connection::open(&paths.db).unwrap();
+        db.execute(
+            "UPDATE users SET is_admin = 1 WHERE norm_name = 'bob'",
+            params![],
+        )
+        .unwrap();
+        store.set_disabled("alice", true).unwrap();
+        last_admin(store.delete_user("bob"));
+        store.delete_user("alice").unwrap();
+        assert_eq!(paths.user_names(), vec!["bob"]);
+    }
+
+    #[test]
+    fn the_oldest_user_from_before_admins_is_made_admin_once() {
+        let paths = Paths::new();
+        paths.write_legacy(&[
+            ("bob", legacy_user(1, "bob")),
+            ("alice", legacy_user(0, "alice")),
+        ]);
+        let store = paths.open().unwrap();
+        assert_eq!(paths.admin_names(), vec!["alice"]);
+
+        let db = Connection::open(&paths.db).unwrap();
+        db.execute("UPDATE users SET is_admin = 0", params![])
+            .unwrap();
+        drop(store);
+        paths.open().unwrap();
+        assert!(paths.admin_names().is_empty());
+    }
+
+    #[test]
+    fn old_password_hashes_are_replaced_on_login() {
+        let paths = Paths::new();
+        let store = paths.open().unwrap();
+        store.register_user("alice", "pw", None).unwrap();
+        paths.set_password_hash("alice", &bcrypt::hash("pw", 4).unwrap());
+
+        assert!(store.authenticate_user("alice", "wrong").is_err());
+        assert!(paths.password_hash("alice").starts_with("$2"));
+        store.authenticate_user("alice", "pw").unwrap();
+        let hash = paths.password_hash("alice");
+        assert!(hash.starts_with("$argon2id$v=19$m=8,t=1,p=1$"));
+        store.authenticate_user("alice", "pw").unwrap();
+        assert_eq!(paths.password_hash("alice"), hash);
+        drop(store);
+
+        // a hash made with a cost that has since been raised
+        let store = paths.open_with(PasswordHasher::new(16, 2, 1)).unwrap();
+        store.authenticate_user("alice", "pw").unwrap();
+        assert!(paths
+            .password_hash("alice")
+            .starts_with("$argon2id$v=19$m=16,t=2,p=1$"));
+    }
+}
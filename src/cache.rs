@@ -1,21 +1,82 @@
 use std::{
-    collections::HashMap,
-    mem,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
     sync::{
-        atomic::{AtomicU8, Ordering},
-        RwLock,
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
     },
+    thread,
     time::{Duration, Instant},
 };
 
-const MAX_OPS_BEFORE_PRUNE: u8 = 16;
+use serde::Serialize;
 
+use crate::constants;
+
+const SHARD_COUNT: usize = 16;
+
+/// Limits on a cache beyond how long its entries last.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheOptions {
+    /// Roughly the most entries to hold. Each shard holds its share of these, and past that the
+    /// shard's least recently used entry is evicted.
+    pub max_entries: Option<usize>,
+    /// Whether reading an entry restarts its expiry, rather than it expiring a fixed time after
+    /// it was inserted.
+    pub sliding: bool,
+    /// How often expired entries are cleared out in the background.
+    pub prune_interval: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_entries: None,
+            sliding: false,
+            prune_interval: Duration::new(constants::CACHE_PRUNE_INTERVAL, 0),
+        }
+    }
+}
+
+/// How a cache has been used since it was made.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for others before they expired.
+    pub evictions: u64,
+    pub expirations: u64,
+}
+
+/// A map whose entries expire. It's split into shards that each have their own lock, and reads
+/// only ever take a shard's read lock, so lookups on different threads don't wait on each other.
+/// Expired entries are never returned, and are removed by a background thread.
 pub struct TtlCache<T>
 where T: Clone
+{
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T>
+where T: Clone
 {
     expiry: Duration,
-    users: RwLock<HashMap<String, TtlEntry<T>>>,
-    elapsed_ops: AtomicU8,
+    options: CacheOptions,
+    /// What the entries' last use times are counted from.
+    epoch: Instant,
+    hasher: RandomState,
+    shards: Vec<Shard<T>>,
+}
+
+struct Shard<T>
+where T: Clone
+{
+    entries: RwLock<HashMap<String, TtlEntry<T>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
 }
 
 struct TtlEntry<T>
@@ -23,35 +84,79 @@ where T: Clone
 {
     value: T,
     created: Instant,
+    /// Nanoseconds from the cache's epoch to when the entry was last read, plus one, or zero if
+    /// it hasn't been read. It's atomic so that reads can update it under the read lock.
+    used: AtomicU64,
 }
 
 impl<T> TtlCache<T>
-where T: Clone
+where T: Clone + Send + Sync + 'static
 {
-    pub fn new(expiry: Duration) -> Self {
-        TtlCache {
-            users: RwLock::new(HashMap::new()),
-            elapsed_ops: AtomicU8::new(0),
+    /// Makes a cache along with the thread that prunes it, which is why the values have to be
+    /// shareable between threads.
+    pub fn with_options(expiry: Duration, options: CacheOptions) -> Self {
+        let shared = Arc::new(Shared {
             expiry,
-        }
+            options,
+            epoch: Instant::now(),
+            hasher: RandomState::new(),
+            shards: (0..SHARD_COUNT)
+                .map(|_| Shard {
+                    entries: RwLock::new(HashMap::new()),
+                    hits: AtomicU64::new(0),
+                    misses: AtomicU64::new(0),
+                    evictions: AtomicU64::new(0),
+                    expirations: AtomicU64::new(0),
+                })
+                .collect(),
+        });
+        prune_periodically(Arc::downgrade(&shared), options.prune_interval);
+        TtlCache { shared }
     }
+}
 
+impl<T> TtlCache<T>
+where T: Clone
+{
     pub fn get_expiry(&self) -> Duration {
-        self.expiry
+        self.shared.expiry
     }
 
     pub fn insert(&self, key: &str, value: T) {
-        let mut users = self.users.write().unwrap();
-        users.insert(
+        self.insert_with_time(key, value, Instant::now());
+    }
+
+    /// Inserts an entry that was created at some earlier time, so that it expires sooner.
+    pub fn insert_with_time(&self, key: &str, value: T, created: Instant) {
+        let shared = &self.shared;
+        let shard = shared.shard(key);
+        let mut entries = shard.entries.write().unwrap();
+
+        if let Some(max_entries) = shared.options.max_entries {
+            let max_entries = (max_entries + SHARD_COUNT - 1) / SHARD_COUNT;
+            if entries.len() >= max_entries && !entries.contains_key(key) {
+                shared.prune_shard(shard, &mut entries);
+            }
+            if entries.len() >= max_entries && !entries.contains_key(key) {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| shared.last_used(entry))
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                    shard.evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        entries.insert(
             String::from(key),
             TtlEntry {
                 value,
-                created: Instant::now(),
+                created,
+                used: AtomicU64::new(0),
             },
         );
-        mem::drop(users);  // Necessary for the RwLock
-
-        self.prune_old_entries(false);
     }
 
     pub fn get(&self, key: &str) -> Option<T> {
@@ -59,42 +164,179 @@ where T: Clone
         Some(value)
     }
 
+    /// Looks up an entry along with when it was created. With sliding expiry this also restarts
+    /// the entry's expiry, but the creation time stays the same.
     pub fn get_with_time(&self, key: &str) -> Option<(T, Instant)> {
-        let users = self.users.read().unwrap();
-        let entry = users.get(key)?;
-        let computed = if entry.created.elapsed() > self.expiry {
-            None
-        } else {
-            Some((entry.value.clone(), entry.created))
-        };
-        mem::drop(users);  // Necessary for the RwLock
+        let shared = &self.shared;
+        let shard = shared.shard(key);
+        let entries = shard.entries.read().unwrap();
+        let now = Instant::now();
 
-        if let None = computed {
-            self.prune_old_entries(true);
-        } else {
-            self.prune_old_entries(false);
+        let found = entries
+            .get(key)
+            .filter(|entry| !shared.is_expired(entry, now));
+        match found {
+            Some(entry) => {
+                if shared.options.sliding || shared.options.max_entries.is_some() {
+                    let since_epoch = now.saturating_duration_since(shared.epoch).as_nanos();
+                    entry.used.store(since_epoch as u64 + 1, Ordering::Relaxed);
+                }
+                shard.hits.fetch_add(1, Ordering::Relaxed);
+                Some((entry.value.clone(), entry.created))
+            }
+            None => {
+                shard.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
         }
-        computed
     }
 
     pub fn remove(&self, key: &str) {
-        {
-            let mut users = self.users.write().unwrap();
-            users.remove(key);
-        }
-        self.prune_old_entries(false);
-    }
-
-    #[inline]
-    fn prune_old_entries(&self, force: bool) {
-        let mut users = self.users.write().unwrap();
-        self.elapsed_ops.fetch_add(1, Ordering::Relaxed);
-        if self.elapsed_ops.load(Ordering::Relaxed) >= MAX_OPS_BEFORE_PRUNE || force {
-            let now = Instant::now();
-            users
-                .retain(|_, v| now.saturating_duration_since(v.created) < self.expiry);
-            self.elapsed_ops.store(0, Ordering::Release);
+        let shard = self.shared.shard(key);
+        shard.entries.write().unwrap().remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.shared
+            .shards
+            .iter()
+            .fold(CacheStats::default(), |stats, shard| CacheStats {
+                entries: stats.entries + shard.entries.read().unwrap().len(),
+                hits: stats.hits + shard.hits.load(Ordering::Relaxed),
+                misses: stats.misses + shard.misses.load(Ordering::Relaxed),
+                evictions: stats.evictions + shard.evictions.load(Ordering::Relaxed),
+                expirations: stats.expirations + shard.expirations.load(Ordering::Relaxed),
+            })
+    }
+}
+
+impl<T> Shared<T>
+where T: Clone
+{
+    fn shard(&self, key: &str) -> &Shard<T> {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+
+    fn last_used(&self, entry: &TtlEntry<T>) -> Instant {
+        match entry.used.load(Ordering::Relaxed) {
+            0 => entry.created,
+            used => self.epoch + Duration::from_nanos(used - 1),
         }
     }
+
+    fn is_expired(&self, entry: &TtlEntry<T>, now: Instant) -> bool {
+        let since = if self.options.sliding {
+            self.last_used(entry)
+        } else {
+            entry.created
+        };
+        now.saturating_duration_since(since) >= self.expiry
+    }
+
+    fn prune_shard(&self, shard: &Shard<T>, entries: &mut HashMap<String, TtlEntry<T>>) {
+        let now = Instant::now();
+        let before = entries.len();
+        entries.retain(|_, entry| !self.is_expired(entry, now));
+        let expired = (before - entries.len()) as u64;
+        shard.expirations.fetch_add(expired, Ordering::Relaxed);
+    }
+}
+
+/// Clears expired entries out of a cache every `interval`, until the cache is dropped. Shards are
+/// pruned one at a time, so only one shard is ever locked for it.
+fn prune_periodically<T>(shared: Weak<Shared<T>>, interval: Duration)
+where T: Clone + Send + Sync + 'static
+{
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => break,
+        };
+        for shard in shared.shards.iter() {
+            let mut entries = shard.entries.write().unwrap();
+            shared.prune_shard(shard, &mut entries);
+        }
+    });
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{CacheOptions, TtlCache, SHARD_COUNT};
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    // Entries are inserted as if they were made most of an expiry ago, so the tests only have
+    // to wait a little for them to expire, and nothing expires early however slow a test runs.
+    const EXPIRY: Duration = Duration::from_secs(10);
+    const AGE: Duration = Duration::from_millis(9500);
+    const WAIT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn entries_expire_a_fixed_time_after_they_were_inserted() {
+        let cache = TtlCache::with_options(EXPIRY, CacheOptions::default());
+        cache.insert_with_time("a", 1, Instant::now() - AGE);
+        assert_eq!(cache.get("a"), Some(1));
+        thread::sleep(WAIT);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn reading_an_entry_restarts_a_sliding_expiry() {
+        let options = CacheOptions {
+            sliding: true,
+            ..CacheOptions::default()
+        };
+        let cache = TtlCache::with_options(EXPIRY, options);
+        let created = Instant::now() - AGE;
+        cache.insert_with_time("read", 1, created);
+        cache.insert_with_time("unread", 2, created);
+        assert_eq!(cache.get("read"), Some(1));
+        thread::sleep(WAIT);
+        assert_eq!(cache.get("read"), Some(1));
+        assert_eq!(cache.get("unread"), None);
+    }
+
+    /// Finds keys that land in the same shard as `key`, since entries are only evicted to make
+    /// room in their own shard.
+    fn keys_sharing_a_shard(cache: &TtlCache<i32>, key: &str, count: usize) -> Vec<String> {
+        let shard = cache.shared.shard(key) as *const _;
+        (0..)
+            .map(|i| format!("key{}", i))
+            .filter(|other| cache.shared.shard(other) as *const _ == shard)
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted_when_full() {
+        let options = CacheOptions {
+            max_entries: Some(2 * SHARD_COUNT),
+            ..CacheOptions::default()
+        };
+        let cache = TtlCache::with_options(EXPIRY, options);
+        let keys = keys_sharing_a_shard(&cache, "read", 2);
+        let (unread, added) = (&keys[0], &keys[1]);
+
+        let now = Instant::now();
+        cache.insert_with_time("read", 1, now - Duration::from_secs(3));
+        cache.insert_with_time(unread, 2, now - Duration::from_secs(2));
+        // read after the other was inserted, so it's the more recently used
+        assert_eq!(cache.get("read"), Some(1));
+        cache.insert(added, 3);
+
+        assert_eq!(cache.get(unread), None);
+        assert_eq!(cache.get("read"), Some(1));
+        assert_eq!(cache.get(added), Some(3));
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.expirations, 0);
+        assert_eq!(stats.entries, 2);
+    }
+}
@@ -1,4 +1,44 @@
 pub const SESSION_COOKIE_NAME: &str = "session-token";
+// the names axios uses by default
+pub const CSRF_COOKIE_NAME: &str = "XSRF-TOKEN";
+pub const CSRF_HEADER_NAME: &str = "X-XSRF-TOKEN";
 pub const INDEX_CACHE_EXPIRY: u64 = 30 * 60;
+pub const SESSION_CACHE_SIZE: usize = 10_000;
+pub const CACHE_PRUNE_INTERVAL: u64 = 60;
 pub const INDEXER_HEAP_SIZE: usize = 3_000_000;
-pub const BCRYPT_ITERATIONS: u32 = 12;
+pub const INDEX_COMMIT_BATCH: usize = 100;
+pub const INDEX_COMMIT_DELAY_MS: u64 = 1000;
+pub const SEARCH_SNIPPET_COUNT: usize = 2;
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+pub const MAX_TAG_LENGTH: usize = 64;
+pub const MAX_DIFF_LINES: usize = 10_000;
+pub const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+pub const ARGON2_ITERATIONS: u32 = 2;
+pub const ARGON2_LANES: u32 = 1;
+pub const TRASH_RETENTION_DAYS: i64 = 30;
+pub const TRASH_PURGE_INTERVAL: u64 = 60 * 60;
+pub const ALLOWED_TAGS: &[&str] = &[
+    "p", "br", "strong", "b", "em", "i", "u", "s", "strike", "h1", "h2", "h3", "h4", "h5", "h6",
+    "ol", "ul", "li", "a", "blockquote", "pre", "code", "span",
+];
+pub const ALLOWED_ATTRIBUTES: &[&str] = &["href", "class"];
+pub const SANITIZED_MARKER: &str = ".sanitized";
+pub const NOTE_LOG_FILE: &str = "notes.wal";
+// recorded with each commit; changing it makes the index be built again
+pub const INDEX_VERSION: u32 = 1;
+pub const SESSION_LAST_USE_RESOLUTION: i64 = 60;
+pub const API_TOKEN_DEFAULT_DAYS: i64 = 90;
+pub const API_TOKEN_MAX_DAYS: i64 = 366;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+pub const TWO_FACTOR_LOGIN_EXPIRY: u64 = 5 * 60;
+pub const TWO_FACTOR_MAX_FAILURES: u32 = 5;
+pub const TOTP_ISSUER: &str = "Soash";
+pub const LOGIN_MAX_USER_FAILURES: u32 = 10;
+pub const LOGIN_MAX_IP_FAILURES: u32 = 50;
+pub const LOGIN_BACKOFF_BASE: u64 = 1;
+pub const LOGIN_LOCKOUT: u64 = 15 * 60;
+pub const MIGRATED_SUFFIX: &str = ".migrated";
+pub const PASSWORD_CHANGE_REQUIRED: &str = "A new password is required";
+pub const INVITE_DEFAULT_DAYS: i64 = 7;
+pub const INVITE_MAX_DAYS: i64 = 30;
@@ -0,0 +1,285 @@
+use serde::Serialize;
+
+const BLOCK_ENDS: &[&str] = &[
+    "</p>", "</div>", "</li>", "</h1>", "</h2>", "</h3>", "</h4>", "</h5>", "</h6>",
+    "</blockquote>", "</pre>", "<br>", "<br/>", "\n",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub text: String,
+}
+
+/// Splits a note body into lines for diffing. The editor writes each paragraph without a line
+/// break, so a line ends after any block-level closing tag as well as at newlines.
+pub fn split_blocks(html: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < html.len() {
+        match BLOCK_ENDS.iter().find(|end| html[i..].starts_with(*end)) {
+            Some(end) => {
+                i += end.len();
+                lines.push(&html[start..i]);
+                start = i;
+            }
+            None => i += html[i..].chars().next().unwrap().len_utf8(),
+        }
+    }
+    if start < html.len() {
+        lines.push(&html[start..]);
+    }
+    lines
+}
+
+/// Computes a line diff from `old` to `new` with Myers' algorithm. It uses the linear space
+/// version, which splits the edit graph at the middle of a shortest edit script and recurses
+/// on each side, so it takes O((N+M)D) time for D changed lines and O(N+M) memory.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    let mut lines = Vec::new();
+    diff_range(old, new, &mut lines);
+    lines
+}
+
+fn push_lines(lines: &mut Vec<DiffLine>, op: DiffOp, texts: &[&str]) {
+    lines.extend(texts.iter().map(|text| DiffLine {
+        op,
+        text: String::from(*text),
+    }));
+}
+
+fn diff_range(old: &[&str], new: &[&str], lines: &mut Vec<DiffLine>) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    push_lines(lines, DiffOp::Equal, &old[..prefix]);
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+
+    if old_middle.is_empty() || new_middle.is_empty() {
+        push_lines(lines, DiffOp::Delete, old_middle);
+        push_lines(lines, DiffOp::Insert, new_middle);
+    } else {
+        // with the common ends trimmed, at least two lines changed, so both sides of the
+        // snake are smaller than the whole
+        let ((x, y), (u, v)) = middle_snake(old_middle, new_middle);
+        diff_range(&old_middle[..x], &new_middle[..y], lines);
+        push_lines(lines, DiffOp::Equal, &old_middle[x..u]);
+        diff_range(&old_middle[u..], &new_middle[v..], lines);
+    }
+    push_lines(lines, DiffOp::Equal, &old[old.len() - suffix..]);
+}
+
+/// Finds the snake, a run of equal lines, in the middle of a shortest edit script from `old` to
+/// `new`, by searching forwards from the start and backwards from the end until the two meet.
+/// Returns where it starts and ends, as positions in `old` and `new`. Both must be non-empty.
+fn middle_snake(old: &[&str], new: &[&str]) -> ((usize, usize), (usize, usize)) {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    // the furthest x reached on each diagonal k = x - y, forwards and backwards, where the
+    // backward search runs over both sequences reversed
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    let at = |k: isize| (k + offset) as usize;
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            };
+            let start = (x, x - k);
+            while x < n && x - k < m && old[x as usize] == new[(x - k) as usize] {
+                x += 1;
+            }
+            forward[at(k)] = x;
+            let reverse_k = delta - k;
+            if odd && reverse_k.abs() < d && x + backward[at(reverse_k)] >= n {
+                return (to_position(start), to_position((x, x - k)));
+            }
+        }
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            };
+            let end = (n - x, m - (x - k));
+            while x < n && x - k < m && old[(n - x - 1) as usize] == new[(m - x + k - 1) as usize] {
+                x += 1;
+            }
+            backward[at(k)] = x;
+            let forward_k = delta - k;
+            if !odd && forward_k.abs() <= d && x + forward[at(forward_k)] >= n {
+                return (to_position((n - x, m - (x - k))), to_position(end));
+            }
+        }
+    }
+    unreachable!("the searches meet by the time half the edits are made")
+}
+
+fn to_position((x, y): (isize, isize)) -> (usize, usize) {
+    (x as usize, y as usize)
+}
+
+/// The changes between two versions of a note.
+#[derive(Debug, Serialize)]
+pub struct NoteDiff {
+    pub title: Vec<DiffLine>,
+    pub body: Vec<DiffLine>,
+}
+
+impl NoteDiff {
+    /// Compares two versions of a note, unless either body has more than `max_lines` lines. The
+    /// diff slows down with the number of changed lines times the total, so bodies that are
+    /// both long and very different would take too long.
+    pub fn new(
+        old_title: &str,
+        old_body: &str,
+        new_title: &str,
+        new_body: &str,
+        max_lines: usize,
+    ) -> Option<Self> {
+        let old_lines = split_blocks(old_body);
+        let new_lines = split_blocks(new_body);
+        if old_lines.len() > max_lines || new_lines.len() > max_lines {
+            return None;
+        }
+        Some(NoteDiff {
+            title: diff_lines(&[old_title], &[new_title]),
+            body: diff_lines(&old_lines, &new_lines),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_lines, split_blocks, DiffOp};
+
+    /// Diffs two lists of lines, writing each line with a prefix for how it changed.
+    fn diff(old: &[&str], new: &[&str]) -> Vec<String> {
+        diff_lines(old, new)
+            .into_iter()
+            .map(|line| {
+                let prefix = match line.op {
+                    DiffOp::Equal => " ",
+                    DiffOp::Insert => "+",
+                    DiffOp::Delete => "-",
+                };
+                format!("{}{}", prefix, line.text)
+            })
+            .collect()
+    }
+
+    /// Checks that the diff turns `old` into `new` and keeps as many lines as possible.
+    fn check(old: &[&str], new: &[&str], kept: usize) {
+        let lines = diff_lines(old, new);
+        let sides = |skip: DiffOp| -> Vec<&str> {
+            lines
+                .iter()
+                .filter(|line| line.op != skip)
+                .map(|line| line.text.as_str())
+                .collect()
+        };
+        assert_eq!(sides(DiffOp::Insert), old);
+        assert_eq!(sides(DiffOp::Delete), new);
+        let equal = lines.iter().filter(|line| line.op == DiffOp::Equal).count();
+        assert_eq!(equal, kept, "{:?} -> {:?}", old, new);
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(
+            diff(&["a", "b", "c"], &["a", "x", "c", "d"]),
+            vec![" a", "-b", "+x", " c", "+d"]
+        );
+        assert_eq!(diff(&[], &["a"]), vec!["+a"]);
+        assert_eq!(diff(&["a"], &[]), vec!["-a"]);
+        assert_eq!(diff(&["a", "b"], &["a", "b"]), vec![" a", " b"]);
+        assert!(diff(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_longest_common_subsequence() {
+        check(
+            &["a", "b", "c", "a", "b", "b", "a"],
+            &["c", "b", "a", "b", "a", "c"],
+            4,
+        );
+        check(&["x", "a", "y", "b", "z"], &["a", "q", "b", "r"], 2);
+        check(&["a", "b", "c", "d"], &["d", "c", "b", "a"], 1);
+        check(&["a", "b"], &["c", "d", "e"], 0);
+        check(&["a", "x", "b", "x", "c"], &["x", "a", "x", "b", "x"], 4);
+    }
+
+    #[test]
+    fn keeps_as_many_lines_as_a_full_lcs_table() {
+        // small alphabets make for lots of repeated lines, which is where the search goes wrong
+        // if it's going to
+        let mut seed = 12_345u32;
+        let mut random_lines = |len: u32| -> Vec<&str> {
+            let count = {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) % len
+            };
+            (0..count)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    ["a", "b", "c"][(seed >> 16) as usize % 3]
+                })
+                .collect()
+        };
+        for _ in 0..500 {
+            let old = random_lines(12);
+            let new = random_lines(12);
+            let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+            for i in (0..old.len()).rev() {
+                for j in (0..new.len()).rev() {
+                    lengths[i][j] = if old[i] == new[j] {
+                        lengths[i + 1][j + 1] + 1
+                    } else {
+                        lengths[i + 1][j].max(lengths[i][j + 1])
+                    };
+                }
+            }
+            check(&old, &new, lengths[0][0]);
+        }
+    }
+
+    #[test]
+    fn diffs_long_bodies_with_few_changes() {
+        let old: Vec<String> = (0..20_000).map(|i| format!("line {}", i)).collect();
+        let mut new = old.clone();
+        new[5_000] = String::from("changed");
+        new.remove(15_000);
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+        check(&old, &new, 19_998);
+    }
+
+    #[test]
+    fn splits_bodies_after_block_ends() {
+        assert_eq!(
+            split_blocks("<p>one</p><p>two<br>three</p>\nfour"),
+            vec!["<p>one</p>", "<p>two<br>", "three</p>", "\n", "four"]
+        );
+    }
+}
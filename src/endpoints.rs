@@ -1,73 +1,462 @@
 pub mod auth {
     use crate::{
-        auth::{self, AuthStore, AuthenticatedUser},
-        cache::TtlCache,
+        auth::{
+            self, ApiToken, AuthStore, AuthenticatedUser, AuthenticationError, CookieSettings,
+            Invite, PendingLogin, PendingLogins, RegistrationMode, SessionUser, TokenScope, User,
+        },
         constants,
+        search::NoteStore,
+        session::{ClientInfo, SessionInfo, SessionStore},
+        throttle::LoginThrottle,
+        totp,
     };
+    use chrono::Duration;
     use rocket::{
-        http::{Cookie, Cookies, Status},
+        http::{Cookies, Header, Status},
         request::Form,
+        response::status::{Accepted, Custom},
         Route, State,
     };
+    use rocket_contrib::json::Json;
+    use serde::{Deserialize, Serialize};
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
 
     #[derive(FromForm)]
     pub struct LoginForm {
         pub username: String,
         pub password: String,
+        /// Only needed when an admin has reset the password.
+        pub new_password: Option<String>,
+    }
+
+    #[derive(FromForm)]
+    pub struct SecondFactorForm {
+        pub challenge: String,
+        pub code: String,
+    }
+
+    #[derive(FromForm)]
+    pub struct PasswordForm {
+        pub old_password: String,
+        pub new_password: String,
+    }
+
+    #[derive(FromForm)]
+    pub struct DeleteAccountForm {
+        pub password: String,
+        /// A TOTP or recovery code, only needed when two-factor authentication is on.
+        pub code: Option<String>,
     }
 
     #[derive(FromForm)]
     pub struct RegisterForm {
         pub username: String,
         pub password: String,
+        /// Only needed when registration is by invite.
+        pub invite: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NewInvite {
+        expires_in_days: Option<i64>,
+    }
+
+    /// A newly created invite. This is the only time the code itself is shown.
+    #[derive(Debug, Serialize)]
+    pub struct CreatedInvite {
+        code: String,
+        #[serde(flatten)]
+        details: Invite,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct RegistrationInfo {
+        mode: RegistrationMode,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NewApiToken {
+        name: String,
+        scope: String,
+        expires_in_days: Option<i64>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct TwoFactorCode {
+        code: String,
+    }
+
+    /// Handed out by the password step of logging in when the user has TOTP turned on, to be
+    /// sent back with a code.
+    #[derive(Debug, Serialize)]
+    pub struct SecondFactorChallenge {
+        challenge: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct TwoFactorEnrolment {
+        secret: String,
+        uri: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct RecoveryCodes {
+        recovery_codes: Vec<String>,
+    }
+
+    /// The answer to anything that checks a password, which is throttled the same way whether
+    /// it's a login or not.
+    #[derive(Debug, Responder)]
+    pub enum LoginResponse {
+        Done(Status),
+        #[response(status = 202)]
+        SecondFactorRequired(Json<SecondFactorChallenge>),
+        #[response(status = 429)]
+        TooManyAttempts(String, Header<'static>),
+        Refused(Custom<String>),
+    }
+
+    impl LoginResponse {
+        fn too_many_attempts(wait: std::time::Duration) -> Self {
+            // round up, so that trying again right on time doesn't get refused
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            LoginResponse::TooManyAttempts(
+                String::from("Too many failed attempts, try again later"),
+                Header::new("Retry-After", seconds.to_string()),
+            )
+        }
+    }
+
+    /// A newly created API token. This is the only time the token itself is shown.
+    #[derive(Debug, Serialize)]
+    pub struct CreatedApiToken {
+        token: String,
+        #[serde(flatten)]
+        details: ApiToken,
     }
 
     #[post("/login", data = "<form>")]
+    #[allow(clippy::too_many_arguments)]
     pub fn login(
         mut cookies: Cookies,
         auth_store: State<AuthStore>,
-        auth_cache: State<TtlCache<AuthenticatedUser>>,
+        sessions: State<SessionStore>,
+        cookie_settings: State<CookieSettings>,
+        pending: State<PendingLogins>,
+        throttle: State<LoginThrottle>,
+        client: ClientInfo,
         form: Form<LoginForm>,
-    ) -> Status {
+    ) -> LoginResponse {
         let username = form.username.trim();
         let password = form.password.trim();
 
+        if let Err(wait) = throttle.check(username, client.ip.as_deref()) {
+            return LoginResponse::too_many_attempts(wait);
+        }
         let result = auth_store.authenticate_user(username, password);
         let user = match result {
             Ok(user) => user,
-            Err(_) => return Status::Unauthorized,
+            Err(AuthenticationError::AccountDisabled) => {
+                return LoginResponse::Refused(Custom(
+                    Status::Forbidden,
+                    String::from("This account has been disabled"),
+                ))
+            }
+            Err(_) => {
+                throttle.record_failure(username, client.ip.as_deref());
+                return LoginResponse::Done(Status::Unauthorized);
+            }
         };
 
-        let token = auth::generate_session_token();
-        auth_cache.insert(
-            &token,
-            AuthenticatedUser {
-                id: user.id,
-                name: user.name,
-                token: token.clone(),
-            },
-        );
+        let new_password = if user.must_change_password {
+            match form.new_password.as_ref().map(|p| p.trim()) {
+                Some(new_password) if new_password != "" && new_password != password => {
+                    Some(String::from(new_password))
+                }
+                _ => {
+                    return LoginResponse::Refused(Custom(
+                        Status::Forbidden,
+                        String::from(constants::PASSWORD_CHANGE_REQUIRED),
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+
+        // the new password waits for the second factor, so a stolen password can't be used to
+        // change it
+        if user.has_two_factor() {
+            let new_password_hash = match new_password.map(|p| auth_store.hash_password(&p)) {
+                None => None,
+                Some(Ok(hash)) => Some(hash),
+                Some(Err(_)) => return LoginResponse::Done(Status::InternalServerError),
+            };
+            let challenge = auth::generate_session_token();
+            pending.0.insert(
+                &challenge,
+                PendingLogin {
+                    name: user.name.to_lowercase(),
+                    new_password_hash,
+                    failures: Arc::new(AtomicU32::new(0)),
+                },
+            );
+            return LoginResponse::SecondFactorRequired(Json(SecondFactorChallenge { challenge }));
+        }
+        if let Some(new_password) = new_password {
+            if auth_store.set_password(&user.name, &new_password).is_err() {
+                return LoginResponse::Done(Status::InternalServerError);
+            }
+        }
+        throttle.record_success(username);
+
+        LoginResponse::Done(start_session(
+            &mut cookies,
+            &sessions,
+            *cookie_settings,
+            &client,
+            &user,
+        ))
+    }
 
-        let auth_cookie = Cookie::build(constants::SESSION_COOKIE_NAME, token)
-            .path("/")
-            .finish();
+    /// The second step of logging in for users with TOTP turned on, taking either a code from
+    /// their authenticator or a recovery code.
+    #[post("/login/2fa", data = "<form>")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn login_second_factor(
+        mut cookies: Cookies,
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        cookie_settings: State<CookieSettings>,
+        pending: State<PendingLogins>,
+        throttle: State<LoginThrottle>,
+        client: ClientInfo,
+        form: Form<SecondFactorForm>,
+    ) -> LoginResponse {
+        let login = match pending.0.get(&form.challenge) {
+            Some(login) => login,
+            None => return LoginResponse::Done(Status::Unauthorized),
+        };
+        let name = login.name;
+        // codes are only six digits, so guessing them is throttled just like passwords
+        if let Err(wait) = throttle.check(&name, client.ip.as_deref()) {
+            return LoginResponse::too_many_attempts(wait);
+        }
+        let user = match auth_store.authenticate_second_factor(&name, &form.code) {
+            Ok(user) => user,
+            Err(_) => {
+                throttle.record_failure(&name, client.ip.as_deref());
+                // the throttle is per user, so a challenge also gets only a few guesses of its own
+                let failures = login.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= constants::TWO_FACTOR_MAX_FAILURES {
+                    pending.0.remove(&form.challenge);
+                }
+                return LoginResponse::Done(Status::Unauthorized);
+            }
+        };
+        pending.0.remove(&form.challenge);
+        throttle.record_success(&name);
+        if let Some(hash) = login.new_password_hash {
+            if auth_store.set_password_hash(&name, hash).is_err() {
+                return LoginResponse::Done(Status::InternalServerError);
+            }
+        }
+
+        LoginResponse::Done(start_session(
+            &mut cookies,
+            &sessions,
+            *cookie_settings,
+            &client,
+            &user,
+        ))
+    }
+
+    fn start_session(
+        cookies: &mut Cookies,
+        sessions: &SessionStore,
+        cookie_settings: CookieSettings,
+        client: &ClientInfo,
+        user: &User,
+    ) -> Status {
+        let token = match sessions.create(user.id, &user.name, client) {
+            Ok(token) => token,
+            Err(_) => return Status::InternalServerError,
+        };
 
-        cookies.add(auth_cookie);
+        for cookie in cookie_settings.session_cookies(&token) {
+            cookies.add(cookie);
+        }
 
         Status::Ok
     }
 
+    /// Starts TOTP enrolment. The returned URI goes in a QR code for the authenticator app.
+    #[post("/2fa/enroll")]
+    pub fn enroll_two_factor(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+    ) -> Result<Json<TwoFactorEnrolment>, Custom<String>> {
+        match auth_store.begin_two_factor(&user.name) {
+            Ok(secret) => Ok(Json(TwoFactorEnrolment {
+                uri: totp::provisioning_uri(constants::TOTP_ISSUER, &user.name, &secret),
+                secret,
+            })),
+            Err(AuthenticationError::TwoFactorAlreadyEnabled) => Err(Custom(
+                Status::Conflict,
+                String::from("Two-factor authentication is already on"),
+            )),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not start two-factor enrolment"),
+            )),
+        }
+    }
+
+    /// Turns TOTP on once the user enters a code from their authenticator. The recovery codes in
+    /// the response aren't shown again.
+    #[post("/2fa/confirm", format = "json", data = "<code>")]
+    pub fn confirm_two_factor(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+        code: Json<TwoFactorCode>,
+    ) -> Result<Json<RecoveryCodes>, Custom<String>> {
+        match auth_store.confirm_two_factor(&user.name, &code.code) {
+            Ok(recovery_codes) => Ok(Json(RecoveryCodes { recovery_codes })),
+            Err(AuthenticationError::InvalidCode) => {
+                Err(Custom(Status::BadRequest, String::from("Invalid code")))
+            }
+            Err(AuthenticationError::TwoFactorAlreadyEnabled) => Err(Custom(
+                Status::Conflict,
+                String::from("Two-factor authentication is already on"),
+            )),
+            Err(AuthenticationError::TwoFactorNotEnabled) => Err(Custom(
+                Status::BadRequest,
+                String::from("Two-factor enrolment hasn't been started"),
+            )),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not turn on two-factor authentication"),
+            )),
+        }
+    }
+
+    #[post("/2fa/disable", format = "json", data = "<code>")]
+    pub fn disable_two_factor(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+        code: Json<TwoFactorCode>,
+    ) -> Result<Status, Custom<String>> {
+        match auth_store.disable_two_factor(&user.name, &code.code) {
+            Ok(()) => Ok(Status::Ok),
+            Err(AuthenticationError::InvalidCode) => {
+                Err(Custom(Status::BadRequest, String::from("Invalid code")))
+            }
+            Err(AuthenticationError::TwoFactorNotEnabled) => Err(Custom(
+                Status::BadRequest,
+                String::from("Two-factor authentication is off"),
+            )),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not turn off two-factor authentication"),
+            )),
+        }
+    }
+
     #[post("/register", data = "<form>")]
-    pub fn register(auth_store: State<AuthStore>, form: Form<RegisterForm>) -> Status {
+    pub fn register(
+        auth_store: State<AuthStore>,
+        form: Form<RegisterForm>,
+    ) -> Result<Status, Custom<String>> {
+        use AuthenticationError::*;
         let username = form.username.trim();
         let password = form.password.trim();
 
         if username == "" || password == "" {
-            return Status::Unauthorized;
+            return Err(Custom(
+                Status::BadRequest,
+                String::from("A username and password are required"),
+            ));
+        }
+        let invite = form.invite.as_ref().map(|i| i.trim());
+        let (status, reason) = match auth_store.register_user(username, password, invite) {
+            Ok(_) => return Ok(Status::Ok),
+            Err(RegistrationClosed) => (Status::Forbidden, "Registration is closed"),
+            Err(InviteRequired) => (Status::Forbidden, "An invite code is required"),
+            Err(InvalidInvite) => (
+                Status::Forbidden,
+                "That invite code is not valid or has expired",
+            ),
+            Err(UsernameTaken) => (Status::Conflict, "That username is taken"),
+            Err(_) => (Status::InternalServerError, "Could not register"),
+        };
+        Err(Custom(status, String::from(reason)))
+    }
+
+    /// Tells the client who may register, so it knows whether to ask for an invite code.
+    #[get("/registration")]
+    pub fn registration(auth_store: State<AuthStore>) -> Json<RegistrationInfo> {
+        Json(RegistrationInfo {
+            mode: auth_store.registration_mode(),
+        })
+    }
+
+    #[post("/invites", format = "json", data = "<invite>")]
+    pub fn create_invite(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+        invite: Json<NewInvite>,
+    ) -> Result<Json<CreatedInvite>, Custom<String>> {
+        if auth_store.registration_mode() != RegistrationMode::Invite {
+            return Err(Custom(
+                Status::BadRequest,
+                String::from("Registration is not by invite"),
+            ));
+        }
+        let days = invite
+            .expires_in_days
+            .unwrap_or(constants::INVITE_DEFAULT_DAYS);
+        if days < 1 || days > constants::INVITE_MAX_DAYS {
+            return Err(Custom(
+                Status::BadRequest,
+                format!(
+                    "Invites must expire within 1 to {} days",
+                    constants::INVITE_MAX_DAYS
+                ),
+            ));
+        }
+
+        match auth_store.create_invite(user.id, Duration::days(days)) {
+            Ok((details, code)) => Ok(Json(CreatedInvite { code, details })),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not create invite"),
+            )),
+        }
+    }
+
+    #[get("/invites")]
+    pub fn invites(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+    ) -> Result<Json<Vec<Invite>>, Custom<String>> {
+        match auth_store.list_invites(user.id) {
+            Ok(invites) => Ok(Json(invites)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not list invites"),
+            )),
         }
-        match auth_store.register_user(username, password) {
-            Ok(_) => Status::Ok,
-            Err(_) => Status::Unauthorized,
+    }
+
+    #[delete("/invites/<id>")]
+    pub fn revoke_invite(auth_store: State<AuthStore>, user: SessionUser, id: String) -> Status {
+        match auth_store.revoke_invite(user.id, &id) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::NotFound,
+            Err(_) => Status::InternalServerError,
         }
     }
 
@@ -78,56 +467,611 @@ pub mod auth {
 
     #[get("/logout")]
     pub fn logout(
-        auth_cache: State<TtlCache<AuthenticatedUser>>,
-        user: AuthenticatedUser,
+        sessions: State<SessionStore>,
+        cookie_settings: State<CookieSettings>,
+        user: SessionUser,
         mut cookies: Cookies,
     ) {
-        auth_cache.remove(&user.name);
+        let _ = sessions.remove(&user.token);
+
+        cookie_settings.remove_session_cookies(&mut cookies);
+    }
+
+    /// Changes the user's password and logs out their other sessions. Checking the old password
+    /// is throttled like a login, so a stolen session can't be used to guess it.
+    #[post("/password", data = "<form>")]
+    pub fn change_password(
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        throttle: State<LoginThrottle>,
+        client: ClientInfo,
+        user: SessionUser,
+        form: Form<PasswordForm>,
+    ) -> LoginResponse {
+        let new_password = form.new_password.trim();
+        if new_password == "" {
+            return LoginResponse::Refused(Custom(
+                Status::BadRequest,
+                String::from("A new password is required"),
+            ));
+        }
+
+        if let Err(wait) = throttle.check(&user.name, client.ip.as_deref()) {
+            return LoginResponse::too_many_attempts(wait);
+        }
+        match auth_store.change_password(&user.name, form.old_password.trim(), new_password) {
+            Ok(()) => throttle.record_success(&user.name),
+            Err(AuthenticationError::IncorrectPassword) => {
+                throttle.record_failure(&user.name, client.ip.as_deref());
+                return LoginResponse::Refused(Custom(
+                    Status::Forbidden,
+                    String::from("Incorrect password"),
+                ));
+            }
+            Err(_) => {
+                return LoginResponse::Refused(Custom(
+                    Status::InternalServerError,
+                    String::from("Could not change password"),
+                ))
+            }
+        }
+        match sessions.revoke_others(&user) {
+            Ok(_) => LoginResponse::Done(Status::Ok),
+            Err(_) => LoginResponse::Refused(Custom(
+                Status::InternalServerError,
+                String::from("Password changed, but could not log out other sessions"),
+            )),
+        }
+    }
+
+    /// Deletes the user along with all of their notes. The password, and the second factor if
+    /// it's on, are asked for again, since there's no undoing this.
+    #[delete("/account", data = "<form>")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn delete_account(
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        note_store: State<NoteStore>,
+        cookie_settings: State<CookieSettings>,
+        throttle: State<LoginThrottle>,
+        client: ClientInfo,
+        user: SessionUser,
+        mut cookies: Cookies,
+        form: Form<DeleteAccountForm>,
+    ) -> LoginResponse {
+        if let Err(wait) = throttle.check(&user.name, client.ip.as_deref()) {
+            return LoginResponse::too_many_attempts(wait);
+        }
+        let account = match auth_store.authenticate_user(&user.name, form.password.trim()) {
+            Ok(account) => account,
+            Err(_) => {
+                throttle.record_failure(&user.name, client.ip.as_deref());
+                return LoginResponse::Refused(Custom(
+                    Status::Forbidden,
+                    String::from("Incorrect password"),
+                ));
+            }
+        };
+        if account.has_two_factor() {
+            let code = form.code.as_ref().map_or("", |code| code.trim());
+            if auth_store
+                .authenticate_second_factor(&user.name, code)
+                .is_err()
+            {
+                throttle.record_failure(&user.name, client.ip.as_deref());
+                return LoginResponse::Refused(Custom(
+                    Status::Forbidden,
+                    String::from("Incorrect code"),
+                ));
+            }
+        }
+        throttle.record_success(&user.name);
+
+        match auth_store.is_last_admin(user.id) {
+            Ok(false) => {}
+            Ok(true) => {
+                return LoginResponse::Refused(Custom(
+                    Status::Conflict,
+                    String::from("The last admin can't delete their account"),
+                ))
+            }
+            Err(_) => {
+                return LoginResponse::Refused(Custom(
+                    Status::InternalServerError,
+                    String::from("Could not delete account"),
+                ))
+            }
+        }
+
+        if remove_account(&auth_store, &sessions, &note_store, user.id, &user.name).is_err() {
+            return LoginResponse::Refused(Custom(
+                Status::InternalServerError,
+                String::from("Could not delete account. An admin can finish deleting it."),
+            ));
+        }
+
+        cookie_settings.remove_session_cookies(&mut cookies);
+        LoginResponse::Done(Status::Ok)
+    }
+
+    /// Deletes a user's sessions, notes and then the user. The user is disabled first, so that
+    /// nothing can write notes for them while the notes are deleted. Every step can be done
+    /// again, so if one fails the account is left disabled, and deleting it again finishes the
+    /// job rather than leaving notes behind with no owner.
+    pub fn remove_account(
+        auth_store: &AuthStore,
+        sessions: &SessionStore,
+        note_store: &NoteStore,
+        id: u64,
+        name: &str,
+    ) -> Result<(), ()> {
+        auth_store
+            .set_disabled(name, true)
+            .and_then(|_| sessions.revoke_all(id))
+            .map_err(|_| ())?;
+        note_store.delete_user_notes(id).map_err(|_| ())?;
+        auth_store.delete_user(name).map_err(|_| ())
+    }
+
+    #[get("/sessions")]
+    pub fn sessions(
+        sessions: State<SessionStore>,
+        user: SessionUser,
+    ) -> Result<Json<Vec<SessionInfo>>, Custom<String>> {
+        match sessions.list(&user) {
+            Ok(list) => Ok(Json(list)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not list sessions"),
+            )),
+        }
+    }
+
+    #[delete("/sessions/<id>")]
+    pub fn revoke_session(
+        sessions: State<SessionStore>,
+        user: SessionUser,
+        id: String,
+    ) -> Status {
+        match sessions.revoke(user.id, &id) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::NotFound,
+            Err(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Logs out everywhere but here.
+    #[delete("/sessions")]
+    pub fn revoke_other_sessions(
+        sessions: State<SessionStore>,
+        user: SessionUser,
+    ) -> Result<Accepted<String>, Custom<String>> {
+        match sessions.revoke_others(&user) {
+            Ok(count) => Ok(Accepted(Some(format!("{}", count)))),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not revoke sessions"),
+            )),
+        }
+    }
+
+    #[post("/tokens", format = "json", data = "<token>")]
+    pub fn create_token(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+        token: Json<NewApiToken>,
+    ) -> Result<Json<CreatedApiToken>, Custom<String>> {
+        let name = token.name.trim();
+        if name.is_empty() {
+            return Err(Custom(Status::BadRequest, String::from("Missing token name")));
+        }
+        let scope = TokenScope::parse(&token.scope).ok_or_else(|| {
+            Custom(
+                Status::BadRequest,
+                format!("Unknown token scope '{}'", token.scope),
+            )
+        })?;
+        let days = token
+            .expires_in_days
+            .unwrap_or(constants::API_TOKEN_DEFAULT_DAYS);
+        if days < 1 || days > constants::API_TOKEN_MAX_DAYS {
+            return Err(Custom(
+                Status::BadRequest,
+                format!(
+                    "Tokens must expire within 1 to {} days",
+                    constants::API_TOKEN_MAX_DAYS
+                ),
+            ));
+        }
+
+        match auth_store.create_api_token(&user.name, name, scope, Duration::days(days)) {
+            Ok((details, token)) => Ok(Json(CreatedApiToken { token, details })),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not create token"),
+            )),
+        }
+    }
+
+    #[get("/tokens")]
+    pub fn tokens(
+        auth_store: State<AuthStore>,
+        user: SessionUser,
+    ) -> Result<Json<Vec<ApiToken>>, Custom<String>> {
+        match auth_store.list_api_tokens(&user.name) {
+            Ok(tokens) => Ok(Json(tokens)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not list tokens"),
+            )),
+        }
+    }
+
+    #[delete("/tokens/<id>")]
+    pub fn revoke_token(auth_store: State<AuthStore>, user: SessionUser, id: String) -> Status {
+        match auth_store.revoke_api_token(&user.name, &id) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::NotFound,
+            Err(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn routes() -> Vec<Route> {
+        routes![
+            login,
+            login_second_factor,
+            register,
+            registration,
+            create_invite,
+            invites,
+            revoke_invite,
+            verify,
+            logout,
+            change_password,
+            delete_account,
+            sessions,
+            revoke_session,
+            revoke_other_sessions,
+            create_token,
+            tokens,
+            revoke_token,
+            enroll_two_factor,
+            confirm_two_factor,
+            disable_two_factor
+        ]
+    }
+}
+
+pub mod admin {
+    use crate::{
+        auth::{AdminUser, AuthStore, AuthenticationError, PendingLogins, User},
+        cache::CacheStats,
+        endpoints::auth::remove_account,
+        search::NoteStore,
+        session::SessionStore,
+        throttle::LoginThrottle,
+    };
+    use rocket::{http::Status, response::status::Custom, Route, State};
+    use rocket_contrib::json::Json;
+    use serde::Serialize;
+
+    #[derive(Debug, Serialize)]
+    pub struct UserSummary {
+        id: u64,
+        name: String,
+        is_admin: bool,
+        disabled: bool,
+        must_change_password: bool,
+        note_count: usize,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct CacheSummary {
+        sessions: CacheStats,
+        pending_logins: CacheStats,
+        login_failures: CacheStats,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct ReindexSummary {
+        indexed: usize,
+    }
+
+    /// A password set by an admin, to be passed on to its user.
+    #[derive(Debug, Serialize)]
+    pub struct TemporaryPassword {
+        temporary_password: String,
+    }
+
+    #[get("/users")]
+    pub fn users(
+        auth_store: State<AuthStore>,
+        note_store: State<NoteStore>,
+        _admin: AdminUser,
+    ) -> Result<Json<Vec<UserSummary>>, Custom<String>> {
+        let users = auth_store.list_users().map_err(|_| {
+            Custom(
+                Status::InternalServerError,
+                String::from("Could not list users"),
+            )
+        })?;
+
+        let summaries = users
+            .into_iter()
+            .map(|user| {
+                Ok(UserSummary {
+                    note_count: note_store.count_notes(user.id)?,
+                    id: user.id,
+                    name: user.name,
+                    is_admin: user.is_admin,
+                    disabled: user.disabled,
+                    must_change_password: user.must_change_password,
+                })
+            })
+            .collect::<tantivy::Result<Vec<UserSummary>>>();
+        match summaries {
+            Ok(summaries) => Ok(Json(summaries)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not count notes"),
+            )),
+        }
+    }
+
+    /// Disables an account and logs it out everywhere. Its notes are kept.
+    #[post("/users/<id>/disable")]
+    pub fn disable_user(
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        admin: AdminUser,
+        id: u64,
+    ) -> Result<Status, Custom<String>> {
+        let user = other_user(&auth_store, &admin, id)?;
+        auth_store
+            .set_disabled(&user.name, true)
+            .and_then(|_| sessions.revoke_all(user.id))
+            .map(|_| Status::Ok)
+            .map_err(|e| match e {
+                AuthenticationError::LastAdmin => Custom(
+                    Status::Conflict,
+                    String::from("The last admin can't be disabled"),
+                ),
+                _ => Custom(
+                    Status::InternalServerError,
+                    String::from("Could not disable user"),
+                ),
+            })
+    }
+
+    #[post("/users/<id>/enable")]
+    pub fn enable_user(
+        auth_store: State<AuthStore>,
+        admin: AdminUser,
+        id: u64,
+    ) -> Result<Status, Custom<String>> {
+        let user = other_user(&auth_store, &admin, id)?;
+        auth_store
+            .set_disabled(&user.name, false)
+            .map(|_| Status::Ok)
+            .map_err(|_| {
+                Custom(
+                    Status::InternalServerError,
+                    String::from("Could not enable user"),
+                )
+            })
+    }
+
+    /// Replaces a user's password with a temporary one and logs them out. They have to choose a
+    /// new password when they next log in.
+    #[post("/users/<id>/reset-password")]
+    pub fn reset_password(
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        admin: AdminUser,
+        id: u64,
+    ) -> Result<Json<TemporaryPassword>, Custom<String>> {
+        let user = other_user(&auth_store, &admin, id)?;
+        let result = auth_store.reset_password(&user.name).and_then(|temporary_password| {
+            sessions.revoke_all(user.id)?;
+            Ok(temporary_password)
+        });
+        match result {
+            Ok(temporary_password) => Ok(Json(TemporaryPassword { temporary_password })),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not reset password"),
+            )),
+        }
+    }
 
-        let auth_cookie = Cookie::build(constants::SESSION_COOKIE_NAME, "")
-            .path("/")
-            .finish();
+    /// Deletes a user along with all of their notes.
+    #[delete("/users/<id>")]
+    pub fn delete_user(
+        auth_store: State<AuthStore>,
+        sessions: State<SessionStore>,
+        note_store: State<NoteStore>,
+        admin: AdminUser,
+        id: u64,
+    ) -> Result<Status, Custom<String>> {
+        let user = other_user(&auth_store, &admin, id)?;
+        match remove_account(&auth_store, &sessions, &note_store, user.id, &user.name) {
+            Ok(()) => Ok(Status::Ok),
+            Err(()) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not delete user"),
+            )),
+        }
+    }
+
+    #[get("/caches")]
+    pub fn caches(
+        sessions: State<SessionStore>,
+        pending: State<PendingLogins>,
+        throttle: State<LoginThrottle>,
+        _admin: AdminUser,
+    ) -> Json<CacheSummary> {
+        Json(CacheSummary {
+            sessions: sessions.cache_stats(),
+            pending_logins: pending.0.stats(),
+            login_failures: throttle.cache_stats(),
+        })
+    }
+
+    /// Throws away the search index and builds it again from the note database.
+    #[post("/reindex")]
+    pub fn reindex(
+        note_store: State<NoteStore>,
+        _admin: AdminUser,
+    ) -> Result<Json<ReindexSummary>, Custom<String>> {
+        match note_store.rebuild_index() {
+            Ok(indexed) => Ok(Json(ReindexSummary { indexed })),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not rebuild the search index"),
+            )),
+        }
+    }
 
-        cookies.remove(auth_cookie);
+    /// Looks up the user an admin wants to change. Admins can't use these endpoints on their own
+    /// account, so that they can't lock themselves out by mistake.
+    fn other_user(
+        auth_store: &AuthStore,
+        admin: &AdminUser,
+        id: u64,
+    ) -> Result<User, Custom<String>> {
+        if id == admin.id {
+            return Err(Custom(
+                Status::BadRequest,
+                String::from("Admins can't change their own account here"),
+            ));
+        }
+        match auth_store.get_user_by_id(id) {
+            Ok(user) => Ok(user),
+            Err(AuthenticationError::UserNotFound) => Err(Custom(
+                Status::NotFound,
+                String::from("User not found"),
+            )),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not load user"),
+            )),
+        }
     }
 
     pub fn routes() -> Vec<Route> {
-        routes![login, register, verify, logout]
+        routes![
+            users,
+            disable_user,
+            enable_user,
+            reset_password,
+            delete_user,
+            caches,
+            reindex
+        ]
     }
 }
 
 pub mod note {
     use crate::{
-        auth::AuthenticatedUser,
-        search::{DocumentId, Note, NoteStore},
+        auth::{AuthenticatedUser, WritingUser},
+        constants,
+        diff::NoteDiff,
+        sanitize::{Sanitizer, StrippedMarkup},
+        search::{
+            self, DateRange, DocumentId, ListOptions, Note, NoteCursor, NotePage, NoteStore,
+            Opstamp, Revision, SearchError, SearchHit, SearchOptions, SortField, TagCount,
+            TrashedNote, UpdateError,
+        },
     };
+    use chrono::{DateTime, Utc};
     use rocket::{
         http::Status,
         response::status::{Accepted, Custom, NotFound},
         Route, State,
     };
     use rocket_contrib::json::Json;
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Deserialize)]
     pub struct NewNote {
         title: String,
         body: String,
+        /// Left out by clients that don't edit tags, in which case an update keeps the note's
+        /// tags as they are.
+        tags: Option<Vec<String>>,
+        /// The version of the note that an update was based on; ignored for new notes.
+        #[serde(default)]
+        version: Option<u64>,
+    }
+
+    /// The response to saving a note, telling the client what was removed from its body.
+    #[derive(Debug, Serialize)]
+    pub struct SavedNote {
+        id: DocumentId,
+        /// Identifies the write; it can be searched once the index has committed this far.
+        opstamp: Opstamp,
+        stripped: StrippedMarkup,
+    }
+
+    /// The response to moving a note in or out of the trash or back to an old revision.
+    #[derive(Debug, Serialize)]
+    pub struct WrittenNote {
+        id: DocumentId,
+        opstamp: Opstamp,
+    }
+
+    #[derive(Debug, Responder)]
+    pub enum UpdateFailure {
+        /// Someone else changed the note first; the client gets the note as it is now.
+        #[response(status = 409)]
+        Conflict(Json<Note>),
+        Other(Custom<String>),
     }
 
-    #[post("/new", format = "json", data = "<note>")]
+    #[derive(Debug, Deserialize)]
+    pub struct TagRename {
+        from: String,
+        to: String,
+    }
+
+    /// Writes are searchable a moment after they're made. Passing `wait=true` holds the
+    /// response until they are, for clients that search straight after writing.
+    fn wait_if_asked(
+        note_store: &NoteStore,
+        wait: Option<bool>,
+        opstamp: Opstamp,
+    ) -> Result<(), Custom<String>> {
+        if !wait.unwrap_or(false) {
+            return Ok(());
+        }
+        note_store.wait_for(opstamp).map_err(|_| {
+            Custom(
+                Status::InternalServerError,
+                String::from("Could not commit note"),
+            )
+        })
+    }
+
+    #[post("/new?<wait>", format = "json", data = "<note>")]
     pub fn new(
         note_store: State<NoteStore>,
-        user: AuthenticatedUser,
+        sanitizer: State<Sanitizer>,
+        user: WritingUser,
         note: Json<NewNote>,
-    ) -> Result<Accepted<String>, Custom<String>> {
-        let note = Note {
-            id: 0,
-            title: note.title.clone(),
-            body: note.body.clone(),
-        };
+        wait: Option<bool>,
+    ) -> Result<Accepted<Json<SavedNote>>, Custom<String>> {
+        let (body, stripped) = sanitizer.clean(&note.body);
+        let tags = note.tags.as_deref().unwrap_or(&[]);
+        let note = Note::new(note.title.clone(), body, tags);
         match note_store.add_note(user.id, note) {
-            Ok(id) => Ok(Accepted(Some(format!("{}", id)))),
+            Ok((id, opstamp)) => {
+                wait_if_asked(&note_store, wait, opstamp)?;
+                Ok(Accepted(Some(Json(SavedNote {
+                    id,
+                    opstamp,
+                    stripped,
+                }))))
+            }
             Err(_) => Err(Custom(
                 Status::InternalServerError,
                 String::from("Could not save note"),
@@ -135,6 +1079,91 @@ pub mod note {
         }
     }
 
+    fn parse_sort(sort: Option<String>) -> Result<Option<SortField>, Custom<String>> {
+        match sort.as_deref() {
+            None => Ok(None),
+            Some(name) => SortField::parse(name).map(Some).ok_or_else(|| {
+                Custom(Status::BadRequest, format!("Cannot sort notes by '{}'", name))
+            }),
+        }
+    }
+
+    fn parse_order(
+        order: Option<String>,
+        default_descending: bool,
+    ) -> Result<bool, Custom<String>> {
+        match order.as_deref() {
+            None => Ok(default_descending),
+            Some("asc") => Ok(false),
+            Some("desc") => Ok(true),
+            Some(other) => Err(Custom(
+                Status::BadRequest,
+                format!("Unknown sort order '{}'", other),
+            )),
+        }
+    }
+
+    fn parse_date_range(
+        after: Option<String>,
+        before: Option<String>,
+    ) -> Result<DateRange, Custom<String>> {
+        let parse = |date: Option<String>| match date {
+            None => Ok(None),
+            Some(date) => DateTime::parse_from_rfc3339(&date)
+                .map(|d| Some(d.with_timezone(&Utc)))
+                .map_err(|_| {
+                    Custom(
+                        Status::BadRequest,
+                        format!("'{}' is not an RFC 3339 date", date),
+                    )
+                }),
+        };
+        Ok(DateRange {
+            after: parse(after)?,
+            before: parse(before)?,
+        })
+    }
+
+    #[get("/?<sort>&<order>&<limit>&<cursor>&<after>&<before>")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn list(
+        note_store: State<NoteStore>,
+        user: AuthenticatedUser,
+        sort: Option<String>,
+        order: Option<String>,
+        limit: Option<usize>,
+        cursor: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+    ) -> Result<Json<NotePage>, Custom<String>> {
+        let sort = parse_sort(sort)?.unwrap_or(SortField::Id);
+        let descending = parse_order(order, false)?;
+        let created = parse_date_range(after, before)?;
+        let cursor = match cursor {
+            None => None,
+            Some(cursor) => Some(NoteCursor::decode(&cursor).ok_or_else(|| {
+                Custom(Status::BadRequest, String::from("Invalid cursor"))
+            })?),
+        };
+
+        let options = ListOptions {
+            sort,
+            descending,
+            limit: limit
+                .unwrap_or(constants::DEFAULT_PAGE_SIZE)
+                .min(constants::MAX_PAGE_SIZE),
+            after: cursor,
+            created,
+        };
+        match note_store.list_notes(user.id, &options) {
+            Ok(page) => Ok(Json(page)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not list notes"),
+            )),
+        }
+    }
+
     #[get("/<id>")]
     pub fn get(
         note_store: State<NoteStore>,
@@ -147,11 +1176,66 @@ pub mod note {
         }
     }
 
-    #[delete("/<id>")]
-    pub fn delete(note_store: State<NoteStore>, user: AuthenticatedUser, id: DocumentId) -> Status {
+    #[delete("/<id>?<wait>")]
+    pub fn delete(
+        note_store: State<NoteStore>,
+        user: WritingUser,
+        id: DocumentId,
+        wait: Option<bool>,
+    ) -> Result<Json<WrittenNote>, Custom<String>> {
         match note_store.delete_note(user.id, id) {
-            Ok(_) => Status::Ok,
-            Err(_) => Status::NotFound,
+            Ok((_, opstamp)) => {
+                wait_if_asked(&note_store, wait, opstamp)?;
+                Ok(Json(WrittenNote { id, opstamp }))
+            }
+            Err(_) => Err(Custom(Status::NotFound, String::from("No such note"))),
+        }
+    }
+
+    #[get("/trash")]
+    pub fn trash(
+        note_store: State<NoteStore>,
+        user: AuthenticatedUser,
+    ) -> Result<Json<Vec<TrashedNote>>, Custom<String>> {
+        match note_store.list_trash(user.id) {
+            Ok(notes) => Ok(Json(notes)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not list the trash"),
+            )),
+        }
+    }
+
+    #[post("/trash/<id>/restore?<wait>")]
+    pub fn restore(
+        note_store: State<NoteStore>,
+        user: WritingUser,
+        id: DocumentId,
+        wait: Option<bool>,
+    ) -> Result<Json<WrittenNote>, Custom<String>> {
+        match note_store.restore_note(user.id, id) {
+            Ok((_, opstamp)) => {
+                wait_if_asked(&note_store, wait, opstamp)?;
+                Ok(Json(WrittenNote { id, opstamp }))
+            }
+            Err(_) => Err(Custom(
+                Status::NotFound,
+                String::from("No such note in the trash"),
+            )),
+        }
+    }
+
+    #[delete("/trash")]
+    pub fn empty_trash(
+        note_store: State<NoteStore>,
+        user: WritingUser,
+    ) -> Result<Accepted<String>, Custom<String>> {
+        match note_store.empty_trash(user.id) {
+            Ok(count) => Ok(Accepted(Some(format!("{}", count)))),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not empty the trash"),
+            )),
         }
     }
 
@@ -172,37 +1256,178 @@ pub mod note {
         }
     }
 
-    #[post("/<id>/update", format = "json", data = "<note>")]
+    #[post("/<id>/update?<wait>", format = "json", data = "<note>")]
     pub fn update(
         note_store: State<NoteStore>,
-        user: AuthenticatedUser,
+        sanitizer: State<Sanitizer>,
+        user: WritingUser,
         id: DocumentId,
         note: Json<NewNote>,
-    ) -> Result<Accepted<String>, Custom<String>> {
-        let note = Note {
-            id: 0,
-            title: note.title.clone(),
-            body: note.body.clone(),
+        wait: Option<bool>,
+    ) -> Result<Accepted<Json<SavedNote>>, UpdateFailure> {
+        let version = note.version.ok_or_else(|| {
+            UpdateFailure::Other(Custom(
+                Status::BadRequest,
+                String::from("Missing note version"),
+            ))
+        })?;
+        let tags = match &note.tags {
+            Some(tags) => tags.clone(),
+            // if the note has changed since, the version check turns the update down anyway
+            None => match note_store.get_note(user.id, id) {
+                Ok(current) => current.tags,
+                Err(_) => {
+                    return Err(UpdateFailure::Other(Custom(
+                        Status::NotFound,
+                        String::from("No such note"),
+                    )))
+                }
+            },
         };
-        match note_store.update_note(user.id, id, note) {
-            Ok(id) => Ok(Accepted(Some(format!("{}", id)))),
-            Err(_) => Err(Custom(
+        let (body, stripped) = sanitizer.clean(&note.body);
+        let note = Note::new(note.title.clone(), body, &tags);
+        match note_store.update_note(user.id, id, note, Some(version)) {
+            Ok(opstamp) => {
+                wait_if_asked(&note_store, wait, opstamp).map_err(UpdateFailure::Other)?;
+                Ok(Accepted(Some(Json(SavedNote {
+                    id,
+                    opstamp,
+                    stripped,
+                }))))
+            }
+            Err(UpdateError::Conflict(current)) => Err(UpdateFailure::Conflict(Json(*current))),
+            Err(UpdateError::NotFound) => Err(UpdateFailure::Other(Custom(
+                Status::NotFound,
+                String::from("No such note"),
+            ))),
+            Err(UpdateError::IndexError(_)) => Err(UpdateFailure::Other(Custom(
                 Status::InternalServerError,
                 String::from("Could not update note"),
+            ))),
+        }
+    }
+
+    #[get("/<id>/revisions")]
+    pub fn revisions(
+        note_store: State<NoteStore>,
+        sanitizer: State<Sanitizer>,
+        user: AuthenticatedUser,
+        id: DocumentId,
+    ) -> Result<Json<Vec<Revision>>, NotFound<()>> {
+        match note_store.list_revisions(user.id, id) {
+            // revisions may predate the current allow-list, so they're cleaned on the way out
+            Ok(mut revisions) => {
+                for revision in revisions.iter_mut() {
+                    revision.body = sanitizer.clean(&revision.body).0;
+                }
+                Ok(Json(revisions))
+            }
+            Err(_) => Err(NotFound(())),
+        }
+    }
+
+    /// Compares revision `from` with revision `to`, or with the current note if `to` is left
+    /// out.
+    #[get("/<id>/revisions/diff?<from>&<to>")]
+    pub fn diff_revisions(
+        note_store: State<NoteStore>,
+        sanitizer: State<Sanitizer>,
+        user: AuthenticatedUser,
+        id: DocumentId,
+        from: u64,
+        to: Option<u64>,
+    ) -> Result<Json<NoteDiff>, Custom<String>> {
+        let not_found = |_| Custom(Status::NotFound, String::from("No such revision"));
+        let old = note_store
+            .get_revision(user.id, id, from)
+            .map_err(not_found)?;
+        let (title, body) = match to {
+            Some(to) => {
+                let new = note_store
+                    .get_revision(user.id, id, to)
+                    .map_err(not_found)?;
+                (new.title, new.body)
+            }
+            None => {
+                let note = note_store.get_note(user.id, id).map_err(not_found)?;
+                (note.title, note.body)
+            }
+        };
+        let diff = NoteDiff::new(
+            &old.title,
+            &sanitizer.clean(&old.body).0,
+            &title,
+            &sanitizer.clean(&body).0,
+            constants::MAX_DIFF_LINES,
+        );
+        diff.map(Json).ok_or_else(|| {
+            Custom(
+                Status::PayloadTooLarge,
+                format!(
+                    "Notes longer than {} lines can't be compared",
+                    constants::MAX_DIFF_LINES
+                ),
+            )
+        })
+    }
+
+    /// Makes an old revision the current version of the note. The version being replaced is
+    /// kept as a new revision, so a restore can itself be undone.
+    #[post("/<id>/revisions/<revision>/restore?<wait>")]
+    pub fn restore_revision(
+        note_store: State<NoteStore>,
+        sanitizer: State<Sanitizer>,
+        user: WritingUser,
+        id: DocumentId,
+        revision: u64,
+        wait: Option<bool>,
+    ) -> Result<Accepted<Json<WrittenNote>>, Custom<String>> {
+        let not_found = |_| Custom(Status::NotFound, String::from("No such revision"));
+        let old = note_store
+            .get_revision(user.id, id, revision)
+            .map_err(not_found)?;
+        let current = note_store.get_note(user.id, id).map_err(not_found)?;
+
+        let (body, _) = sanitizer.clean(&old.body);
+        let note = Note::new(old.title, body, &current.tags);
+        match note_store.update_note(user.id, id, note, Some(current.version)) {
+            Ok(opstamp) => {
+                wait_if_asked(&note_store, wait, opstamp)?;
+                Ok(Accepted(Some(Json(WrittenNote { id, opstamp }))))
+            }
+            Err(UpdateError::Conflict(_)) => Err(Custom(
+                Status::Conflict,
+                String::from("The note was changed while restoring it"),
+            )),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not restore note"),
             )),
         }
     }
 
-    #[get("/search?<query>&<count>")]
+    #[get("/search?<query>&<count>&<sort>&<order>&<after>&<before>")]
+    #[allow(clippy::too_many_arguments)]
     pub fn search(
         note_store: State<NoteStore>,
         user: AuthenticatedUser,
         query: String,
         count: Option<usize>,
-    ) -> Result<Json<Vec<Note>>, Custom<String>> {
-        let count = count.unwrap_or(10);
-        match note_store.search_notes(user.id, &query, count) {
-            Ok(notes) => Ok(Json(notes)),
+        sort: Option<String>,
+        order: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+    ) -> Result<Json<Vec<SearchHit>>, Custom<String>> {
+        let options = SearchOptions {
+            sort: parse_sort(sort)?,
+            // newest first is the more useful default when sorting search results by date
+            descending: parse_order(order, true)?,
+            count: count.unwrap_or(10),
+            created: parse_date_range(after, before)?,
+        };
+        match note_store.search_notes(user.id, &query, &options) {
+            Ok(hits) => Ok(Json(hits)),
+            Err(SearchError::InvalidQuery(e)) => Err(Custom(Status::BadRequest, e.to_string())),
             Err(_) => Err(Custom(
                 Status::InternalServerError,
                 String::from("Could not search notes"),
@@ -210,8 +1435,59 @@ pub mod note {
         }
     }
 
+    #[get("/tags")]
+    pub fn tags(
+        note_store: State<NoteStore>,
+        user: AuthenticatedUser,
+    ) -> Result<Json<Vec<TagCount>>, Custom<String>> {
+        match note_store.tag_counts(user.id) {
+            Ok(counts) => Ok(Json(counts)),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not count tags"),
+            )),
+        }
+    }
+
+    #[post("/tags/rename", format = "json", data = "<rename>")]
+    pub fn rename_tag(
+        note_store: State<NoteStore>,
+        user: WritingUser,
+        rename: Json<TagRename>,
+    ) -> Result<Accepted<String>, Custom<String>> {
+        let from = search::normalize_tag(&rename.from);
+        let to = search::normalize_tag(&rename.to);
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Err(Custom(Status::BadRequest, String::from("Invalid tag"))),
+        };
+        match note_store.rename_tag(user.id, &from, &to) {
+            Ok(count) => Ok(Accepted(Some(format!("{}", count)))),
+            Err(_) => Err(Custom(
+                Status::InternalServerError,
+                String::from("Could not rename tag"),
+            )),
+        }
+    }
+
     pub fn routes() -> Vec<Route> {
-        routes![new, get, update, delete, search, similar]
+        routes![
+            list,
+            new,
+            get,
+            update,
+            delete,
+            search,
+            similar,
+            tags,
+            rename_tag,
+            revisions,
+            diff_revisions,
+            restore_revision,
+            trash,
+            restore,
+            empty_trash
+        ]
     }
 }
 
@@ -273,3 +1549,642 @@ pub mod static_files {
         routes![root, static_file]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::auth::remove_account;
+    use crate::{
+        auth::{AuthStore, PendingLogins, TokenScope},
+        constants,
+        repository::{NoteRepository, SqliteNoteRepository},
+        search::{Note, NoteStore},
+        session::SessionStore,
+        totp,
+    };
+    use rocket::{
+        config::{Config, ConfigBuilder, Environment, LoggingLevel, Value as ConfigValue},
+        http::{ContentType, Header, SameSite, Status, StatusClass},
+        local::{Client, LocalRequest, LocalResponse},
+    };
+    use serde_json::{json, Value};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+
+    /// A server that keeps everything in a fresh directory, which goes when the server does.
+    struct TestServer {
+        client: Client,
+        dir: PathBuf,
+        /// The CSRF token of the session the client is logged in with.
+        csrf: String,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            TestServer::in_dir(fresh_dir())
+        }
+
+        /// Starts a server with one setting changed from the usual test settings.
+        fn with_setting(key: &str, value: impl Into<ConfigValue>) -> Self {
+            let dir = fresh_dir();
+            let config = TestServer::config(&dir).extra(key, value);
+            TestServer::start(dir, config)
+        }
+
+        /// Starts a server on whatever is already in `dir`.
+        fn in_dir(dir: PathBuf) -> Self {
+            let config = TestServer::config(&dir);
+            TestServer::start(dir, config)
+        }
+
+        fn start(dir: PathBuf, config: ConfigBuilder) -> Self {
+            let config = config.finalize().unwrap();
+            let client = Client::new(crate::build(rocket::custom(config))).unwrap();
+            TestServer {
+                client,
+                dir,
+                csrf: String::new(),
+            }
+        }
+
+        /// The settings for a server that keeps everything in `dir`.
+        fn config(dir: &Path) -> ConfigBuilder {
+            let path = |name: &str| String::from(dir.join(name).to_str().unwrap());
+            Config::build(Environment::Development)
+                .log_level(LoggingLevel::Off)
+                .extra("index_dir", path("index"))
+                .extra("note_db", path("notes.db"))
+                .extra("auth_store", path("auth.db"))
+                .extra("user_db", path("users.db"))
+                .extra("session_store", path("sessions.db"))
+                // passwords hashed at the real cost would make the tests slow
+                .extra("argon2_memory_kib", 8)
+                .extra("argon2_iterations", 1)
+                .extra("login_backoff_base", 0)
+        }
+
+        /// Registers a user with the password "pw" and logs the client in as them.
+        fn log_in(&mut self, name: &str) {
+            let form = format!("username={}&password=pw", name);
+            let csrf = {
+                let response = self.post_form("/api/auth/register", &form);
+                assert_eq!(response.status(), Status::Ok);
+                let response = self.post_form("/api/auth/login", &form);
+                assert_eq!(response.status(), Status::Ok);
+                response
+                    .cookies()
+                    .into_iter()
+                    .find(|cookie| cookie.name() == constants::CSRF_COOKIE_NAME)
+                    .map(|cookie| String::from(cookie.value()))
+            };
+            self.csrf = csrf.unwrap();
+        }
+
+        fn post_form(&self, path: &str, form: &str) -> LocalResponse {
+            self.client
+                .post(String::from(path))
+                .header(ContentType::Form)
+                .header(Header::new(constants::CSRF_HEADER_NAME, self.csrf.clone()))
+                .body(form)
+                .dispatch()
+        }
+
+        fn delete_form(&self, path: &str, form: &str) -> LocalResponse {
+            self.client
+                .delete(String::from(path))
+                .header(ContentType::Form)
+                .header(Header::new(constants::CSRF_HEADER_NAME, self.csrf.clone()))
+                .body(form)
+                .dispatch()
+        }
+
+        fn post_json(&self, path: &str, body: Value) -> LocalResponse {
+            self.client
+                .post(String::from(path))
+                .header(ContentType::JSON)
+                .header(Header::new(constants::CSRF_HEADER_NAME, self.csrf.clone()))
+                .body(body.to_string())
+                .dispatch()
+        }
+
+        fn get_json(&self, path: &str) -> Value {
+            let mut response = self.client.get(path).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        }
+
+        /// Creates an API token for the logged in user, returning the token itself and its id.
+        fn create_token(&self, scope: &str) -> (String, String) {
+            let body = json!({"name": scope, "scope": scope});
+            let mut response = self.post_json("/api/auth/tokens", body);
+            assert_eq!(response.status(), Status::Ok);
+            let created: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            let field = |name: &str| String::from(created[name].as_str().unwrap());
+            (field("token"), field("id"))
+        }
+
+        /// Turns on TOTP for the logged in user, returning their secret and the time of the code
+        /// that confirmed it, which can't be used again.
+        fn enable_two_factor(&self) -> (String, i64) {
+            let mut response = self.post_form("/api/auth/2fa/enroll", "");
+            assert_eq!(response.status(), Status::Ok);
+            let enrolment: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            let secret = String::from(enrolment["secret"].as_str().unwrap());
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+            let code = totp::code_at(&secret, now);
+            let response = self.post_json("/api/auth/2fa/confirm", json!({ "code": code }));
+            assert_eq!(response.status(), Status::Ok);
+            (secret, now)
+        }
+
+        /// Logs in with a password, returning the challenge for the second factor.
+        fn start_login(&self, form: &str) -> String {
+            let mut response = self.post_form("/api/auth/login", form);
+            assert_eq!(response.status(), Status::Accepted);
+            let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            String::from(body["challenge"].as_str().unwrap())
+        }
+
+        fn second_factor(&self, challenge: &str, code: &str) -> Status {
+            let form = format!("challenge={}&code={}", challenge, code);
+            self.post_form("/api/auth/login/2fa", &form).status()
+        }
+    }
+
+    fn fresh_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("soash-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            // the index and caches are still being written to from their own threads, which
+            // can leave files behind
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn updates_without_tags_keep_them() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let mut response = server.post_json(
+            "/api/note/new",
+            json!({"title": "Pasta", "body": "boil", "tags": ["food"]}),
+        );
+        assert_eq!(response.status(), Status::Accepted);
+        let saved: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let path = format!("/api/note/{}", saved["id"]);
+
+        let response = server.post_json(
+            &format!("{}/update", path),
+            json!({"title": "Pasta", "body": "boil water", "version": 1}),
+        );
+        assert_eq!(response.status(), Status::Accepted);
+        let note = server.get_json(&path);
+        assert_eq!(note["body"], "boil water");
+        assert_eq!(note["tags"], json!(["food"]));
+
+        // tags that are sent replace the note's, even when there are none
+        let response = server.post_json(
+            &format!("{}/update", path),
+            json!({"title": "Pasta", "body": "boil water", "tags": [], "version": 2}),
+        );
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(server.get_json(&path)["tags"], json!([]));
+    }
+
+    #[test]
+    fn a_challenge_is_dropped_after_too_many_wrong_codes() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let (secret, confirmed_at) = server.enable_two_factor();
+        // the next step's code, since the one that confirmed TOTP is used up
+        let code = totp::code_at(&secret, confirmed_at + 30);
+
+        let challenge = server.start_login("username=alice&password=pw");
+        for _ in 0..constants::TWO_FACTOR_MAX_FAILURES {
+            assert_eq!(
+                server.second_factor(&challenge, "wrong"),
+                Status::Unauthorized
+            );
+        }
+        assert_eq!(
+            server.second_factor(&challenge, &code),
+            Status::Unauthorized
+        );
+
+        let challenge = server.start_login("username=alice&password=pw");
+        assert_eq!(server.second_factor(&challenge, &code), Status::Ok);
+    }
+
+    #[test]
+    fn a_new_password_waits_for_the_second_factor() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let (secret, confirmed_at) = server.enable_two_factor();
+        let code = totp::code_at(&secret, confirmed_at + 30);
+        let auth_store = server.client.rocket().state::<AuthStore>().unwrap();
+        let temporary = auth_store.reset_password("alice").unwrap();
+
+        let form = format!("username=alice&password={}&new_password=new", temporary);
+        let challenge = server.start_login(&form);
+        let pending = server.client.rocket().state::<PendingLogins>().unwrap();
+        let login = pending.0.get(&challenge).unwrap();
+        let hash = login.new_password_hash.unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert_eq!(
+            server.second_factor(&challenge, "wrong"),
+            Status::Unauthorized
+        );
+        let response = server.post_form("/api/auth/login", "username=alice&password=new");
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        assert_eq!(server.second_factor(&challenge, &code), Status::Ok);
+        server.start_login("username=alice&password=new");
+    }
+
+    #[test]
+    fn the_sanitized_marker_is_carried_over_from_the_index() {
+        let dir = fresh_dir();
+        fs::create_dir_all(dir.join("index")).unwrap();
+        fs::write(dir.join("index").join(constants::SANITIZED_MARKER), "").unwrap();
+        let note_db = String::from(dir.join("notes.db").to_str().unwrap());
+        // a body the sanitizer would change, standing in for one that was allowed when it was
+        // cleaned
+        let body = String::from(r#"<p onclick="go()">Hi</p>"#);
+        let id = SqliteNoteRepository::open(&note_db)
+            .unwrap()
+            .add(1, &Note::new(String::from("Hi"), body.clone(), &[]))
+            .unwrap();
+
+        let _server = TestServer::in_dir(dir);
+        let repository = SqliteNoteRepository::open(&note_db).unwrap();
+        assert_eq!(repository.get(1, id).unwrap().body, body);
+        assert!(Path::new(&format!("{}{}", note_db, constants::SANITIZED_MARKER)).exists());
+    }
+
+    #[test]
+    fn restoring_a_revision_keeps_the_replaced_version_as_the_next_revision() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let mut response = server.post_json(
+            "/api/note/new",
+            json!({"title": "Soup", "body": "<p>one</p>"}),
+        );
+        let saved: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let path = format!("/api/note/{}", saved["id"]);
+        for (version, body) in [(1, "<p>two</p>"), (2, "<p>three</p>")].iter() {
+            let response = server.post_json(
+                &format!("{}/update", path),
+                json!({"title": "Soup", "body": body, "version": version}),
+            );
+            assert_eq!(response.status(), Status::Accepted);
+        }
+
+        let response = server.post_form(&format!("{}/revisions/1/restore", path), "");
+        assert_eq!(response.status(), Status::Accepted);
+        let note = server.get_json(&path);
+        assert_eq!(note["body"], "<p>one</p>");
+        assert_eq!(note["version"], 4);
+        let revisions = server.get_json(&format!("{}/revisions", path));
+        let numbered: Vec<_> = revisions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|revision| (revision["revision"].clone(), revision["body"].clone()))
+            .collect();
+        assert_eq!(
+            numbered,
+            vec![
+                (json!(3), json!("<p>three</p>")),
+                (json!(2), json!("<p>two</p>")),
+                (json!(1), json!("<p>one</p>")),
+            ]
+        );
+
+        let diff = server.get_json(&format!("{}/revisions/diff?from=3", path));
+        assert_eq!(
+            diff["body"],
+            json!([
+                {"op": "delete", "text": "<p>three</p>"},
+                {"op": "insert", "text": "<p>one</p>"},
+            ])
+        );
+    }
+
+    #[test]
+    fn bodies_too_long_to_diff_are_refused() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let long_body = "<p>line</p>".repeat(constants::MAX_DIFF_LINES + 1);
+        let mut response = server.post_json(
+            "/api/note/new",
+            json!({"title": "Log", "body": "<p>short</p>"}),
+        );
+        let saved: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let path = format!("/api/note/{}", saved["id"]);
+        let response = server.post_json(
+            &format!("{}/update", path),
+            json!({"title": "Log", "body": long_body, "version": 1}),
+        );
+        assert_eq!(response.status(), Status::Accepted);
+
+        let response = server
+            .client
+            .get(format!("{}/revisions/diff?from=1", path))
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+
+    /// Registers `name` with the password "pw", returning the status and the reason given.
+    fn register(server: &TestServer, name: &str, invite: &str) -> (Status, String) {
+        let form = format!("username={}&password=pw&invite={}", name, invite);
+        let mut response = server.post_form("/api/auth/register", &form);
+        let reason = response.body_string().unwrap_or_default();
+        (response.status(), reason)
+    }
+
+    #[test]
+    fn only_the_first_user_can_register_when_registration_is_closed() {
+        let mut server = TestServer::with_setting("registration", "closed");
+        server.log_in("alice");
+        let (status, reason) = register(&server, "bob", "");
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(reason, "Registration is closed");
+        let response = server.post_form("/api/auth/login", "username=bob&password=pw");
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn an_invite_lets_one_user_register() {
+        let mut server = TestServer::with_setting("registration", "invite");
+        server.log_in("alice");
+        let (status, reason) = register(&server, "bob", "");
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(reason, "An invite code is required");
+        let invalid = "That invite code is not valid or has expired";
+        assert_eq!(register(&server, "bob", "made-up").1, invalid);
+
+        let auth_store = server.client.rocket().state::<AuthStore>().unwrap();
+        let alice = auth_store.authenticate_user("alice", "pw").unwrap();
+        let (_, expired) = auth_store
+            .create_invite(alice.id, chrono::Duration::seconds(-1))
+            .unwrap();
+        assert_eq!(register(&server, "bob", &expired).1, invalid);
+
+        let mut response = server.post_json("/api/auth/invites", json!({}));
+        assert_eq!(response.status(), Status::Ok);
+        let invite: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let code = invite["code"].as_str().unwrap();
+        assert_eq!(register(&server, "bob", code).0, Status::Ok);
+        let (status, reason) = register(&server, "carol", code);
+        assert_eq!(status, Status::Forbidden);
+        assert_eq!(reason, invalid);
+    }
+
+    /// Whether a server starts with `key` set to `value`.
+    fn starts_with(key: &str, value: i64) -> bool {
+        let dir = fresh_dir();
+        let config = TestServer::config(&dir)
+            .extra(key, value)
+            .finalize()
+            .unwrap();
+        let started = match Client::new(crate::build(rocket::custom(config))) {
+            Ok(_) => true,
+            Err(e) => {
+                // a launch error panics when dropped unless it has been looked at
+                e.kind();
+                false
+            }
+        };
+        let _ = fs::remove_dir_all(&dir);
+        started
+    }
+
+    #[test]
+    fn the_trash_must_be_kept_for_at_least_a_day() {
+        assert!(starts_with("trash_retention_days", 1));
+        assert!(!starts_with("trash_retention_days", 0));
+        assert!(!starts_with("trash_retention_days", -5));
+    }
+
+    /// Sends `request` with an API token and no session, returning the status of the response.
+    fn with_token(request: LocalRequest, token: &str) -> Status {
+        let bearer = Header::new("Authorization", format!("Bearer {}", token));
+        request.header(bearer).dispatch().status()
+    }
+
+    #[test]
+    fn read_only_tokens_cannot_change_notes() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let mut response = server.post_json(
+            "/api/note/new?wait=true",
+            json!({"title": "Pasta", "body": "boil"}),
+        );
+        let saved: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let note = format!("/api/note/{}", saved["id"]);
+        let (read, _) = server.create_token("read");
+        let (read_write, _) = server.create_token("read-write");
+
+        let client = &server.client;
+        let body = json!({"title": "Pasta", "body": "boil well", "version": 1}).to_string();
+        assert_eq!(with_token(client.get(note.clone()), &read), Status::Ok);
+        let writes = || {
+            vec![
+                client
+                    .post("/api/note/new")
+                    .header(ContentType::JSON)
+                    .body(body.clone()),
+                client
+                    .post(format!("{}/update", note))
+                    .header(ContentType::JSON)
+                    .body(body.clone()),
+                client.delete(note.clone()),
+                client.delete("/api/note/trash"),
+            ]
+        };
+        for request in writes() {
+            assert_eq!(with_token(request, &read), Status::Forbidden);
+        }
+        for request in writes() {
+            let status = with_token(request, &read_write);
+            assert_eq!(status.class(), StatusClass::Success);
+        }
+    }
+
+    #[test]
+    fn expired_and_revoked_tokens_are_refused() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let client = &server.client;
+        let verify = |token: &str| with_token(client.get("/api/auth/verify"), token);
+        let (token, id) = server.create_token("read");
+        assert_eq!(verify(&token), Status::Ok);
+        let response = server.delete_form(&format!("/api/auth/tokens/{}", id), "");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(verify(&token), Status::Unauthorized);
+
+        let auth_store = client.rocket().state::<AuthStore>().unwrap();
+        let lifetime = chrono::Duration::seconds(-1);
+        let (_, expired) = auth_store
+            .create_api_token("alice", "old", TokenScope::Read, lifetime)
+            .unwrap();
+        assert_eq!(verify(&expired), Status::Unauthorized);
+    }
+
+    #[test]
+    fn changes_made_with_a_session_need_the_csrf_token() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let new_note = |csrf: Option<&str>| {
+            let mut request = server
+                .client
+                .post("/api/note/new")
+                .header(ContentType::JSON)
+                .body(json!({"title": "Pasta", "body": "boil"}).to_string());
+            if let Some(csrf) = csrf {
+                let csrf = String::from(csrf);
+                request = request.header(Header::new(constants::CSRF_HEADER_NAME, csrf));
+            }
+            request.dispatch().status()
+        };
+        assert_eq!(new_note(None), Status::Forbidden);
+        assert_eq!(new_note(Some("wrong")), Status::Forbidden);
+        assert_eq!(new_note(Some(&server.csrf)), Status::Accepted);
+        // reading doesn't need it
+        let response = server.client.get("/api/auth/verify").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn requests_with_an_api_token_need_no_csrf_token() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let (token, _) = server.create_token("read-write");
+        let request = server
+            .client
+            .post("/api/note/new")
+            .header(ContentType::JSON)
+            .body(json!({"title": "Pasta", "body": "boil"}).to_string());
+        assert_eq!(with_token(request, &token), Status::Accepted);
+    }
+
+    #[test]
+    fn only_the_csrf_cookie_can_be_read_by_scripts() {
+        for &secure in [false, true].iter() {
+            let server = TestServer::with_setting("cookie_secure", secure);
+            let form = "username=alice&password=pw";
+            server.post_form("/api/auth/register", form);
+            let response = server.post_form("/api/auth/login", form);
+            assert_eq!(response.status(), Status::Ok);
+            let cookies = response.cookies();
+            let cookie = |name| cookies.iter().find(|c| c.name() == name).unwrap();
+
+            let session = cookie(constants::SESSION_COOKIE_NAME);
+            assert_eq!(session.http_only(), Some(true));
+            let csrf = cookie(constants::CSRF_COOKIE_NAME);
+            assert_ne!(csrf.http_only(), Some(true));
+            for cookie in &[session, csrf] {
+                assert_eq!(cookie.secure().unwrap_or(false), secure);
+                assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+                assert_eq!(cookie.path(), Some("/"));
+            }
+        }
+    }
+
+    #[test]
+    fn logins_must_be_allowed_at_least_one_failure() {
+        for key in &["login_max_user_failures", "login_max_ip_failures"] {
+            assert!(starts_with(key, 1));
+            assert!(!starts_with(key, 0));
+            assert!(!starts_with(key, -1));
+        }
+    }
+
+    #[test]
+    fn the_only_admin_cannot_delete_their_account() {
+        let mut server = TestServer::new();
+        server.log_in("alice");
+        let response = server.delete_form("/api/auth/account", "password=pw");
+        assert_eq!(response.status(), Status::Conflict);
+        let response = server.post_form("/api/auth/login", "username=alice&password=pw");
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn guessing_the_password_of_a_session_is_throttled() {
+        let mut server = TestServer::new();
+        server.log_in("root");
+        server.log_in("alice");
+        for _ in 0..constants::LOGIN_MAX_USER_FAILURES {
+            let form = "old_password=guess&new_password=new";
+            let response = server.post_form("/api/auth/password", form);
+            assert_eq!(response.status(), Status::Forbidden);
+        }
+
+        let form = "old_password=pw&new_password=new";
+        let response = server.post_form("/api/auth/password", form);
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = server.delete_form("/api/auth/account", "password=pw");
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = server.post_form("/api/auth/login", "username=alice&password=pw");
+        assert_eq!(response.status(), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn deleting_an_account_with_two_factor_needs_a_code() {
+        let mut server = TestServer::new();
+        server.log_in("root");
+        server.log_in("alice");
+        let (secret, confirmed_at) = server.enable_two_factor();
+        let code = totp::code_at(&secret, confirmed_at + 30);
+
+        let response = server.delete_form("/api/auth/account", "password=pw");
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = server.delete_form("/api/auth/account", "password=pw&code=wrong");
+        assert_eq!(response.status(), Status::Forbidden);
+        let form = format!("password=pw&code={}", code);
+        let response = server.delete_form("/api/auth/account", &form);
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = server.post_form("/api/auth/login", "username=alice&password=pw");
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn an_account_deletion_that_stopped_part_way_can_be_finished() {
+        let mut server = TestServer::new();
+        // the first user is the admin, who can't be deleted while the only one
+        server.log_in("root");
+        server.log_in("alice");
+        let mut response = server.post_json(
+            "/api/note/new?wait=true",
+            json!({"title": "Pasta", "body": "boil"}),
+        );
+        let saved: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let note_id = saved["id"].as_u64().unwrap() as usize;
+
+        let rocket = server.client.rocket();
+        let auth_store = rocket.state::<AuthStore>().unwrap();
+        let sessions = rocket.state::<SessionStore>().unwrap();
+        let note_store = rocket.state::<NoteStore>().unwrap();
+        let user = auth_store.authenticate_user("alice", "pw").unwrap();
+        // as if an earlier attempt got as far as deleting the notes
+        auth_store.set_disabled("alice", true).unwrap();
+        sessions.revoke_all(user.id).unwrap();
+        note_store.delete_user_notes(user.id).unwrap();
+
+        assert!(remove_account(auth_store, sessions, note_store, user.id, "alice").is_ok());
+        assert!(note_store.get_note(user.id, note_id).is_err());
+        assert!(auth_store.get_user_by_id(user.id).is_err());
+    }
+}
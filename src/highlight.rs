@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, BTreeSet};
+use tantivy::{
+    query::{BooleanQuery, Occur, Query},
+    schema::Field,
+    Index, Searcher, Term,
+};
+
+const CONTEXT_BEFORE: usize = 8;
+const CONTEXT_AFTER: usize = 16;
+const MAX_SNIPPET_TOKENS: usize = 40;
+const BLOCK_TAGS: &[&str] = &[
+    "p", "div", "br", "li", "ul", "ol", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "pre",
+    "tr", "td", "th",
+];
+
+/// Byte ranges of the markup (tags and comments) in an HTML string, so text positions can be
+/// told apart from positions inside a tag.
+pub struct TagSpans {
+    spans: Vec<(usize, usize)>,
+}
+
+impl TagSpans {
+    pub fn new(html: &str) -> Self {
+        let bytes = html.as_bytes();
+        let mut spans = Vec::new();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            if bytes[i] != b'<' || i + 1 >= bytes.len() {
+                i += 1;
+                continue;
+            }
+
+            let next = bytes[i + 1];
+            let end = if html[i..].starts_with("<!--") {
+                html[i + 4..].find("-->").map(|e| i + 4 + e + 3)
+            } else if next.is_ascii_alphabetic() || next == b'/' || next == b'!' || next == b'?' {
+                let mut quote = None;
+                let mut j = i + 1;
+                loop {
+                    match (bytes.get(j), quote) {
+                        (None, _) => break None,
+                        (Some(&c), Some(q)) if c == q => quote = None,
+                        (Some(_), Some(_)) => {}
+                        (Some(&c), None) if c == b'"' || c == b'\'' => quote = Some(c),
+                        (Some(b'>'), None) => break Some(j + 1),
+                        _ => {}
+                    }
+                    j += 1;
+                }
+            } else {
+                // a stray '<' in text
+                i += 1;
+                continue;
+            };
+
+            let end = end.unwrap_or_else(|| bytes.len());
+            spans.push((i, end));
+            i = end;
+        }
+
+        TagSpans { spans }
+    }
+
+    /// Returns whether the byte at `pos` is part of a tag.
+    pub fn contains(&self, pos: usize) -> bool {
+        match self.spans.binary_search_by(|(start, _)| start.cmp(&pos)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => pos < self.spans[i - 1].1,
+        }
+    }
+
+    fn within(&self, from: usize, to: usize) -> impl Iterator<Item = &(usize, usize)> {
+        self.spans
+            .iter()
+            .skip_while(move |(_, end)| *end <= from)
+            .take_while(move |(start, _)| *start < to)
+    }
+}
+
+struct SourceToken {
+    text: String,
+    offset_from: usize,
+    offset_to: usize,
+}
+
+/// Weights each of the query's terms for `field` by how rare it is, so rare terms make for
+/// better snippets than common ones. Terms from excluded clauses are left out, since matches
+/// can't contain them.
+pub fn query_terms(searcher: &Searcher, query: &dyn Query, field: Field) -> BTreeMap<String, f32> {
+    let mut terms = BTreeSet::new();
+    wanted_terms(query, &mut terms);
+    terms
+        .into_iter()
+        .filter(|term: &Term| term.field() == field)
+        .filter_map(|term| {
+            let doc_freq = searcher.doc_freq(&term);
+            if doc_freq > 0 {
+                Some((term.text().to_string(), 1. / (1. + doc_freq as f32)))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn wanted_terms(query: &dyn Query, terms: &mut BTreeSet<Term>) {
+    match query.downcast_ref::<BooleanQuery>() {
+        Some(query) => {
+            for (occur, clause) in query.clauses() {
+                if *occur != Occur::MustNot {
+                    wanted_terms(clause.as_ref(), terms);
+                }
+            }
+        }
+        None => query.query_terms(terms),
+    }
+}
+
+/// Picks up to `count` passages of `html` containing the most valuable of `terms`, with the
+/// matched words wrapped in `<mark>`. If nothing matches, the start of the text is returned
+/// instead so that there is always something to show.
+pub fn snippets(
+    index: &Index,
+    field: Field,
+    html: &str,
+    terms: &BTreeMap<String, f32>,
+    count: usize,
+) -> Vec<String> {
+    let tags = TagSpans::new(html);
+
+    let mut tokens = Vec::new();
+    if let Ok(tokenizer) = index.tokenizer_for_field(field) {
+        let mut stream = tokenizer.token_stream(html);
+        while let Some(token) = stream.next() {
+            tokens.push(SourceToken {
+                text: token.text.clone(),
+                offset_from: token.offset_from,
+                offset_to: token.offset_to,
+            });
+        }
+    }
+
+    let mut windows: Vec<(usize, usize, f32)> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let score = match terms.get(&token.text) {
+            Some(score) => *score,
+            None => continue,
+        };
+        let start = i.saturating_sub(CONTEXT_BEFORE);
+        let end = (i + CONTEXT_AFTER).min(tokens.len());
+        match windows.last_mut() {
+            Some(last) if last.1 >= start && end - last.0 <= MAX_SNIPPET_TOKENS => {
+                last.1 = end;
+                last.2 += score;
+            }
+            Some(last) if last.1 > i => last.2 += score,
+            Some(last) => {
+                let start = start.max(last.1);
+                windows.push((start, end, score));
+            }
+            None => windows.push((start, end, score)),
+        }
+    }
+
+    if windows.is_empty() {
+        if tokens.is_empty() {
+            return vec![];
+        }
+        windows.push((0, (CONTEXT_BEFORE + CONTEXT_AFTER).min(tokens.len()), 0.));
+    }
+
+    windows.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+    windows.truncate(count);
+    windows.sort_by_key(|w| w.0);
+
+    windows
+        .into_iter()
+        .map(|(start, end, _)| {
+            render(
+                html,
+                &tags,
+                &tokens[start..end],
+                terms,
+                start > 0,
+                end < tokens.len(),
+            )
+        })
+        .collect()
+}
+
+fn render(
+    html: &str,
+    tags: &TagSpans,
+    tokens: &[SourceToken],
+    terms: &BTreeMap<String, f32>,
+    leading: bool,
+    trailing: bool,
+) -> String {
+    let from = tokens.first().unwrap().offset_from;
+    let to = tokens.last().unwrap().offset_to.max(from);
+
+    let mut out = String::new();
+    if leading {
+        out.push_str("… ");
+    }
+
+    let mut highlighted = tokens
+        .iter()
+        .filter(|t| t.offset_to > t.offset_from && terms.contains_key(&t.text))
+        .peekable();
+    let mut pos = from;
+    let mut spans = tags.within(from, to).peekable();
+
+    while pos < to {
+        if let Some(&&(start, end)) = spans.peek() {
+            if start <= pos {
+                if is_block_tag(&html[start..end]) && !out.ends_with(' ') {
+                    out.push(' ');
+                }
+                pos = end;
+                spans.next();
+                continue;
+            }
+        }
+
+        let mut next = spans.peek().map(|s| s.0).unwrap_or(to).min(to);
+        if let Some(token) = highlighted.peek() {
+            if token.offset_from <= pos {
+                let end = token.offset_to.min(next).max(pos);
+                out.push_str("<mark>");
+                out.push_str(&html[pos..end]);
+                out.push_str("</mark>");
+                pos = end;
+                highlighted.next();
+                continue;
+            }
+            next = next.min(token.offset_from);
+        }
+
+        out.push_str(&html[pos..next]);
+        pos = next;
+    }
+
+    if trailing {
+        out.push_str(" …");
+    }
+    out.trim().to_string()
+}
+
+fn is_block_tag(tag: &str) -> bool {
+    let name: String = tag
+        .trim_start_matches('<')
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    BLOCK_TAGS.contains(&name.to_lowercase().as_str())
+}
@@ -5,8 +5,10 @@ extern crate rocket;
 #[macro_use]
 extern crate tantivy;
 extern crate base64;
+extern crate chrono;
 extern crate pickledb;
 extern crate rocket_contrib;
+extern crate rusqlite;
 #[macro_use]
 extern crate rust_embed;
 extern crate serde;
@@ -15,16 +17,31 @@ extern crate uuid;
 mod auth;
 mod cache;
 mod constants;
+mod diff;
 mod endpoints;
+mod highlight;
+mod password;
+mod query;
+mod repository;
+mod sanitize;
 mod search;
+mod session;
+mod throttle;
+mod totp;
+mod wal;
 
 use crate::{
-    auth::{AuthStore, AuthenticatedUser},
-    cache::TtlCache,
-    search::NoteStore,
+    auth::{AuthStore, AuthenticationError, CookieSettings, PendingLogins, RegistrationMode},
+    cache::{CacheOptions, TtlCache},
+    password::PasswordHasher,
+    repository::SqliteNoteRepository,
+    sanitize::Sanitizer,
+    search::{CommitPolicy, NoteStore},
+    session::SessionStore,
+    throttle::{LoginThrottle, ThrottleLimits},
 };
-use rocket::fairing::AdHoc;
-use std::time::Duration;
+use rocket::{config::Value, fairing::AdHoc, Rocket};
+use std::{fs, path::Path, sync::Arc, time::Duration};
 
 #[derive(Clone)]
 pub struct Config {
@@ -33,23 +50,208 @@ pub struct Config {
 }
 
 fn main() {
-    rocket::ignite()
+    build(rocket::ignite()).launch();
+}
+
+/// Mounts the routes on `rocket`, along with everything they use, set up from its config.
+fn build(rocket: Rocket) -> Rocket {
+    rocket
         .mount("/api/auth", endpoints::auth::routes())
+        .mount("/api/admin", endpoints::admin::routes())
         .mount("/api/note", endpoints::note::routes())
         .mount("/", endpoints::static_files::routes())
         .attach(AdHoc::on_attach("Config Loader", |rocket| {
             let config = rocket.config();
 
             let index_dir = config.get_str("index_dir").unwrap_or("./index").to_string();
+            let note_db_path = config
+                .get_str("note_db")
+                .unwrap_or("./notes.db")
+                .to_string();
             let auth_store_path = config
                 .get_str("auth_store")
                 .unwrap_or("./auth.db")
                 .to_string();
+            let user_db_path = config
+                .get_str("user_db")
+                .unwrap_or("./users.db")
+                .to_string();
+            let session_store_path = config
+                .get_str("session_store")
+                .unwrap_or("./sessions.db")
+                .to_string();
+            let string_list = |key: &str, default: &[&str]| -> Vec<String> {
+                match config.get_slice(key) {
+                    Ok(values) => values
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(String::from)
+                        .collect(),
+                    Err(_) => default.iter().map(|s| String::from(*s)).collect(),
+                }
+            };
+            let sanitizer = Sanitizer::new(
+                string_list("allowed_tags", constants::ALLOWED_TAGS),
+                string_list("allowed_attributes", constants::ALLOWED_ATTRIBUTES),
+            );
+            let login_limit = |key: &str, default: u64| -> u64 {
+                config
+                    .get_int(key)
+                    .ok()
+                    .filter(|n| *n >= 0)
+                    .map_or(default, |n| n as u64)
+            };
+            // a limit of no failures would lock everyone out on their first attempt
+            let max_failures = |key: &str, default: u32| match config.get_int(key) {
+                Ok(n) if n < 1 => {
+                    println!("{} must be at least 1, not {}", key, n);
+                    None
+                }
+                Ok(n) => Some(n.min(u32::max_value().into()) as u32),
+                Err(_) => Some(default),
+            };
+            let max_user_failures = max_failures(
+                "login_max_user_failures",
+                constants::LOGIN_MAX_USER_FAILURES,
+            );
+            let max_ip_failures =
+                max_failures("login_max_ip_failures", constants::LOGIN_MAX_IP_FAILURES);
+            let (max_user_failures, max_ip_failures) = match (max_user_failures, max_ip_failures) {
+                (Some(user), Some(ip)) => (user, ip),
+                _ => return Err(rocket),
+            };
+            let throttle_limits = ThrottleLimits {
+                max_user_failures,
+                max_ip_failures,
+                backoff_base: Duration::new(
+                    login_limit("login_backoff_base", constants::LOGIN_BACKOFF_BASE),
+                    0,
+                ),
+                lockout: Duration::new(login_limit("login_lockout", constants::LOGIN_LOCKOUT), 0),
+            };
+            let trash_retention_days = config
+                .get_int("trash_retention_days")
+                .unwrap_or(constants::TRASH_RETENTION_DAYS);
+            if trash_retention_days < 1 {
+                println!(
+                    "trash_retention_days must be at least 1, not {}",
+                    trash_retention_days
+                );
+                return Err(rocket);
+            }
 
-            let auth_cache: TtlCache<AuthenticatedUser> =
-                TtlCache::new(Duration::new(constants::INDEX_CACHE_EXPIRY, 0));
-            let auth_store = AuthStore::new(&auth_store_path);
-            let note_store = match NoteStore::new(index_dir) {
+            let session_cache_size = config
+                .get_int("session_cache_size")
+                .ok()
+                .filter(|n| *n > 0)
+                .map_or(constants::SESSION_CACHE_SIZE, |n| n as usize);
+            let cache_options = CacheOptions {
+                prune_interval: Duration::new(
+                    config
+                        .get_int("cache_prune_interval")
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .map_or(constants::CACHE_PRUNE_INTERVAL, |n| n as u64),
+                    0,
+                ),
+                ..CacheOptions::default()
+            };
+            let sessions = SessionStore::new(
+                &session_store_path,
+                Duration::new(constants::INDEX_CACHE_EXPIRY, 0),
+                CacheOptions {
+                    max_entries: Some(session_cache_size),
+                    ..cache_options
+                },
+            );
+            let same_site = config.get_str("cookie_same_site").unwrap_or("strict");
+            let cookie_settings = CookieSettings {
+                secure: config.get_bool("cookie_secure").unwrap_or(false),
+                same_site: match CookieSettings::parse_same_site(same_site) {
+                    Some(same_site) => same_site,
+                    None => {
+                        println!("Unknown cookie_same_site setting '{}'", same_site);
+                        return Err(rocket);
+                    }
+                },
+            };
+            let admin_user = config.get_str("admin_user").ok().map(String::from);
+            let registration = config.get_str("registration").unwrap_or("open");
+            let registration = match RegistrationMode::parse(registration) {
+                Some(mode) => mode,
+                None => {
+                    println!("Unknown registration mode '{}'", registration);
+                    return Err(rocket);
+                }
+            };
+            let argon2_setting = |key: &str, default: u32| -> u32 {
+                config
+                    .get_int(key)
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .map_or(default, |n| n as u32)
+            };
+            let hasher = PasswordHasher::new(
+                argon2_setting("argon2_memory_kib", constants::ARGON2_MEMORY_KIB),
+                argon2_setting("argon2_iterations", constants::ARGON2_ITERATIONS),
+                argon2_setting("argon2_lanes", constants::ARGON2_LANES),
+            );
+            let auth_store = match AuthStore::new(
+                &user_db_path,
+                &auth_store_path,
+                admin_user,
+                registration,
+                hasher,
+            ) {
+                Ok(store) => store,
+                Err(AuthenticationError::MigrationConflict(users)) => {
+                    println!(
+                        "Could not move the users in {} into the user database, so none were \
+                         moved. These users need fixing first:",
+                        auth_store_path
+                    );
+                    for user in users {
+                        println!("  {}", user);
+                    }
+                    return Err(rocket);
+                }
+                Err(e) => {
+                    println!("Could not open the user database: {:?}", e);
+                    return Err(rocket);
+                }
+            };
+            let commit_policy = CommitPolicy {
+                max_pending: config
+                    .get_int("index_commit_batch")
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .map_or(constants::INDEX_COMMIT_BATCH, |n| n as usize),
+                max_delay: Duration::from_millis(
+                    config
+                        .get_int("index_commit_delay_ms")
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .map_or(constants::INDEX_COMMIT_DELAY_MS, |n| n as u64),
+                ),
+            };
+            let repository = match SqliteNoteRepository::open(&note_db_path) {
+                Ok(repository) => repository,
+                Err(e) => {
+                    println!("Could not open the note database: {:?}", e);
+                    return Err(rocket);
+                }
+            };
+            // the marker used to be kept in the index, which moving the notes out of the index
+            // renames, so it's carried over first
+            let sanitized_marker = format!("{}{}", note_db_path, constants::SANITIZED_MARKER);
+            let legacy_marker = Path::new(&index_dir).join(constants::SANITIZED_MARKER);
+            if legacy_marker.exists() && !Path::new(&sanitized_marker).exists() {
+                if let Err(e) = fs::write(&sanitized_marker, "") {
+                    println!("Could not record that notes were sanitized: {:?}", e);
+                    return Err(rocket);
+                }
+            }
+            let note_store = match NoteStore::new(index_dir, Arc::new(repository), commit_policy) {
                 Ok(store) => store,
                 Err(e) => {
                     println!("{:?}", e);
@@ -57,11 +259,47 @@ fn main() {
                 }
             };
 
+            // notes saved before bodies were sanitized are cleaned once, the first time the
+            // server starts with this note database
+            if !Path::new(&sanitized_marker).exists() {
+                let result = note_store.rewrite_notes(|note| {
+                    let (body, stripped) = sanitizer.clean(&note.body);
+                    // bodies are re-serialized even when nothing is stripped, and those that
+                    // only differ in how they're written are left alone
+                    if stripped.is_empty() {
+                        return false;
+                    }
+                    println!(
+                        "Sanitized note {}: stripped tags {:?}, attributes {:?}",
+                        note.id, stripped.tags, stripped.attributes
+                    );
+                    note.body = body;
+                    true
+                });
+                match result.and_then(|_| fs::write(&sanitized_marker, "").map_err(From::from)) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("Could not sanitize existing notes: {:?}", e);
+                        return Err(rocket);
+                    }
+                }
+            }
+            note_store.purge_trash_periodically(
+                chrono::Duration::days(trash_retention_days),
+                Duration::new(constants::TRASH_PURGE_INTERVAL, 0),
+            );
+
             Ok(rocket
-                .manage(auth_cache)
+                .manage(sessions)
+                .manage(cookie_settings)
+                .manage(PendingLogins(TtlCache::with_options(
+                    Duration::new(constants::TWO_FACTOR_LOGIN_EXPIRY, 0),
+                    cache_options,
+                )))
+                .manage(LoginThrottle::new(throttle_limits, cache_options))
                 .manage(auth_store)
-                .manage(note_store))
+                .manage(note_store)
+                .manage(sanitizer))
         }))
         .attach(auth::TokenRefreshFairing {})
-        .launch();
 }
@@ -0,0 +1,102 @@
+//! Password hashing. New hashes use Argon2id; bcrypt hashes from before are still accepted, and
+//! are replaced the next time their user logs in.
+
+use argon2::{Config, ThreadMode, Variant, Version};
+use bcrypt::BcryptError;
+use ring::rand::{SecureRandom, SystemRandom};
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: u32 = 32;
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+const BCRYPT_PREFIXES: &[&str] = &["$2a$", "$2b$", "$2x$", "$2y$"];
+
+#[derive(Debug)]
+pub enum PasswordError {
+    Argon2(argon2::Error),
+    Bcrypt(BcryptError),
+    /// The stored hash isn't in a format this knows.
+    UnknownFormat,
+    RandomUnavailable,
+}
+
+impl From<argon2::Error> for PasswordError {
+    fn from(error: argon2::Error) -> Self {
+        Self::Argon2(error)
+    }
+}
+
+impl From<BcryptError> for PasswordError {
+    fn from(error: BcryptError) -> Self {
+        Self::Bcrypt(error)
+    }
+}
+
+/// Hashes and checks passwords with the configured Argon2id cost.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordHasher {
+    /// Memory used per hash, in KiB.
+    memory_kib: u32,
+    iterations: u32,
+    lanes: u32,
+}
+
+impl PasswordHasher {
+    pub fn new(memory_kib: u32, iterations: u32, lanes: u32) -> Self {
+        PasswordHasher {
+            memory_kib,
+            iterations,
+            lanes,
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| PasswordError::RandomUnavailable)?;
+        Ok(argon2::hash_encoded(
+            password.as_bytes(),
+            &salt,
+            &self.config(),
+        )?)
+    }
+
+    /// Checks a password against a stored hash of either kind.
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError> {
+        if hash.starts_with(ARGON2ID_PREFIX) {
+            Ok(argon2::verify_encoded(hash, password.as_bytes())?)
+        } else if BCRYPT_PREFIXES.iter().any(|prefix| hash.starts_with(prefix)) {
+            Ok(bcrypt::verify(password, hash)?)
+        } else {
+            Err(PasswordError::UnknownFormat)
+        }
+    }
+
+    /// Whether a stored hash was made some other way than this would make it now, either with
+    /// another algorithm or with a different cost.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        // like $argon2id$v=19$m=19456,t=2,p=1$salt$hash
+        let mut fields = hash.split('$').skip(1);
+        if fields.next() != Some("argon2id") || fields.next() != Some("v=19") {
+            return true;
+        }
+        let expected = format!(
+            "m={},t={},p={}",
+            self.memory_kib, self.iterations, self.lanes
+        );
+        fields.next() != Some(expected.as_str())
+    }
+
+    fn config(&self) -> Config<'static> {
+        Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.memory_kib,
+            time_cost: self.iterations,
+            lanes: self.lanes,
+            thread_mode: ThreadMode::from_threads(self.lanes),
+            hash_length: HASH_LENGTH,
+            ..Config::default()
+        }
+    }
+}
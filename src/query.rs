@@ -0,0 +1,567 @@
+//! Parser for the note search query language.
+//!
+//! The grammar, from loosest to tightest binding:
+//!
+//! ```text
+//! query   := or
+//! or      := and ("OR" and)*
+//! and     := clauses ("AND" clauses)*
+//! clauses := clause+
+//! clause  := ["+" | "-"] [field ":"] atom
+//! atom    := word | "\"" phrase "\"" | "(" query ")"
+//! ```
+//!
+//! Plain clauses are optional and only affect ranking, `+` makes a clause required and `-`
+//! excludes it. Both sides of an `AND` are required, while an `OR` matches either side.
+//! Unqualified terms are searched in every default field. Prefixing a term with a facet field's
+//! name, like `tag:work`, matches that facet exactly instead. Any other word followed by a
+//! colon, like `todo:` or `http:`, is just text.
+
+use std::fmt;
+use tantivy::{
+    query::{AllQuery, BooleanQuery, EmptyQuery, Occur, PhraseQuery, Query, TermQuery},
+    schema::{Facet, Field, FieldType, IndexRecordOption},
+    Index, Term,
+};
+
+use crate::search;
+
+#[derive(Debug)]
+pub struct QueryParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl QueryParseError {
+    fn new(position: usize, message: String) -> Self {
+        QueryParseError { position, message }
+    }
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Phrase(String),
+    Field(String),
+    Plus,
+    Minus,
+    LParen,
+    RParen,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+fn describe(kind: &TokenKind) -> String {
+    match kind {
+        TokenKind::Word(w) => format!("'{}'", w),
+        TokenKind::Phrase(p) => format!("\"{}\"", p),
+        TokenKind::Field(f) => format!("'{}:'", f),
+        TokenKind::Plus => String::from("'+'"),
+        TokenKind::Minus => String::from("'-'"),
+        TokenKind::LParen => String::from("'('"),
+        TokenKind::RParen => String::from("')'"),
+        TokenKind::And => String::from("AND"),
+        TokenKind::Or => String::from("OR"),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && c != '(' && c != ')' && c != '"'
+}
+
+fn tokenize(text: &str, fields: &[&str]) -> Result<Vec<Token>, QueryParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    // `+` and `-` are only operators at the start of a clause, so "e-mail" stays one word.
+    let mut clause_start = true;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() {
+            clause_start = true;
+            i += 1;
+            continue;
+        }
+
+        let kind = match c {
+            '(' => {
+                i += 1;
+                TokenKind::LParen
+            }
+            ')' => {
+                i += 1;
+                TokenKind::RParen
+            }
+            '+' if clause_start => {
+                i += 1;
+                TokenKind::Plus
+            }
+            '-' if clause_start => {
+                i += 1;
+                TokenKind::Minus
+            }
+            '"' => {
+                i += 1;
+                let phrase_start = i;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(QueryParseError::new(
+                        start,
+                        String::from("Unterminated phrase"),
+                    ));
+                }
+                let phrase: String = chars[phrase_start..i].iter().collect();
+                i += 1;
+                TokenKind::Phrase(phrase)
+            }
+            _ => {
+                while i < chars.len() && is_word_char(chars[i]) {
+                    // a field prefix ends the word, as long as it names one of the fields
+                    if chars[i] == ':' {
+                        let name: String = chars[start..i].iter().collect();
+                        if fields.contains(&name.as_str()) {
+                            break;
+                        }
+                    }
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                if i < chars.len() && chars[i] == ':' {
+                    i += 1;
+                    TokenKind::Field(word)
+                } else if word == "AND" {
+                    TokenKind::And
+                } else if word == "OR" {
+                    TokenKind::Or
+                } else {
+                    TokenKind::Word(word)
+                }
+            }
+        };
+
+        clause_start = match kind {
+            TokenKind::LParen | TokenKind::Plus | TokenKind::Minus | TokenKind::Field(_) => true,
+            _ => false,
+        };
+        tokens.push(Token {
+            kind,
+            position: start,
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// A parsed query, independent of any index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryAst {
+    Term { field: Option<String>, text: String },
+    Phrase { field: Option<String>, text: String },
+    Boolean(Vec<(Occur, QueryAst)>),
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    index: usize,
+    end: usize,
+    fields: &'a [&'a str],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.index).map(|t| &t.kind)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map(|t| t.position)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn unexpected(&self) -> QueryParseError {
+        match self.tokens.get(self.index) {
+            Some(token) => QueryParseError::new(
+                token.position,
+                format!("Unexpected {}", describe(&token.kind)),
+            ),
+            None => QueryParseError::new(self.end, String::from("Unexpected end of query")),
+        }
+    }
+
+    fn parse_or(&mut self, field: Option<&str>) -> Result<QueryAst, QueryParseError> {
+        let mut children = vec![self.parse_and(field)?];
+        while let Some(TokenKind::Or) = self.peek() {
+            self.next();
+            children.push(self.parse_and(field)?);
+        }
+        Ok(combine(Occur::Should, children))
+    }
+
+    fn parse_and(&mut self, field: Option<&str>) -> Result<QueryAst, QueryParseError> {
+        let mut children = vec![self.parse_clauses(field)?];
+        while let Some(TokenKind::And) = self.peek() {
+            self.next();
+            children.push(self.parse_clauses(field)?);
+        }
+        Ok(combine(Occur::Must, children))
+    }
+
+    fn parse_clauses(&mut self, field: Option<&str>) -> Result<QueryAst, QueryParseError> {
+        let mut clauses = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(TokenKind::RParen) | Some(TokenKind::And) | Some(TokenKind::Or) => {
+                    break
+                }
+                _ => clauses.push(self.parse_clause(field)?),
+            }
+        }
+        match clauses.len() {
+            0 => Err(self.unexpected()),
+            1 if clauses[0].0 == Occur::Should => Ok(clauses.pop().unwrap().1),
+            _ => Ok(QueryAst::Boolean(clauses)),
+        }
+    }
+
+    fn parse_clause(&mut self, field: Option<&str>) -> Result<(Occur, QueryAst), QueryParseError> {
+        let occur = match self.peek() {
+            Some(TokenKind::Plus) => {
+                self.next();
+                Occur::Must
+            }
+            Some(TokenKind::Minus) => {
+                self.next();
+                Occur::MustNot
+            }
+            _ => Occur::Should,
+        };
+
+        let field = match self.peek() {
+            Some(TokenKind::Field(name)) => {
+                let known = self.fields.iter().find(|f| *f == name).cloned();
+                self.next();
+                known
+            }
+            _ => field,
+        };
+
+        Ok((occur, self.parse_atom(field)?))
+    }
+
+    fn parse_atom(&mut self, field: Option<&str>) -> Result<QueryAst, QueryParseError> {
+        let field_name = field.map(String::from);
+        match self.peek() {
+            Some(TokenKind::Word(_)) | Some(TokenKind::Phrase(_)) => {
+                match self.next().unwrap().kind {
+                    TokenKind::Word(text) => Ok(QueryAst::Term {
+                        field: field_name,
+                        text,
+                    }),
+                    TokenKind::Phrase(text) => Ok(QueryAst::Phrase {
+                        field: field_name,
+                        text,
+                    }),
+                    _ => unreachable!(),
+                }
+            }
+            Some(TokenKind::LParen) => {
+                let open = self.position();
+                self.next();
+                let inner = self.parse_or(field)?;
+                match self.peek() {
+                    Some(TokenKind::RParen) => {
+                        self.next();
+                        Ok(inner)
+                    }
+                    None => Err(QueryParseError::new(
+                        open,
+                        String::from("Unclosed parenthesis"),
+                    )),
+                    _ => Err(self.unexpected()),
+                }
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+fn combine(occur: Occur, mut children: Vec<QueryAst>) -> QueryAst {
+    if children.len() == 1 {
+        children.pop().unwrap()
+    } else {
+        QueryAst::Boolean(children.into_iter().map(|c| (occur, c)).collect())
+    }
+}
+
+/// Parses `text` into a query tree. Only the names in `fields` are taken as field prefixes.
+pub fn parse(text: &str, fields: &[&str]) -> Result<QueryAst, QueryParseError> {
+    let tokens = tokenize(text, fields)?;
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: text.chars().count(),
+        fields,
+    };
+
+    let ast = parser.parse_or(None)?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected());
+    }
+    Ok(ast)
+}
+
+/// Parses `text` and builds a tantivy query for it. Unqualified terms are searched in all of
+/// `default_fields`, and only the names in `named_fields` are taken as prefixes.
+pub fn build_query(
+    index: &Index,
+    default_fields: &[Field],
+    named_fields: &[(&str, Field)],
+    text: &str,
+) -> Result<Box<dyn Query>, QueryParseError> {
+    let names: Vec<&str> = named_fields.iter().map(|(name, _)| *name).collect();
+    let ast = parse(text, &names)?;
+
+    let fields = QueryFields {
+        default: default_fields,
+        named: named_fields,
+    };
+    Ok(to_query(index, &fields, &ast).unwrap_or_else(|| Box::new(EmptyQuery)))
+}
+
+struct QueryFields<'a> {
+    default: &'a [Field],
+    named: &'a [(&'a str, Field)],
+}
+
+fn to_query(index: &Index, fields: &QueryFields, ast: &QueryAst) -> Option<Box<dyn Query>> {
+    match ast {
+        QueryAst::Term { field, text } | QueryAst::Phrase { field, text } => {
+            let fields = match field {
+                Some(name) => {
+                    let (_, field) = fields.named.iter().find(|(n, _)| n == name).unwrap();
+                    vec![*field]
+                }
+                None => fields.default.to_vec(),
+            };
+            let mut queries: Vec<(Occur, Box<dyn Query>)> = fields
+                .into_iter()
+                .filter_map(|f| text_query(index, f, text))
+                .map(|q| (Occur::Should, q))
+                .collect();
+            match queries.len() {
+                0 => None,
+                1 => Some(queries.pop().unwrap().1),
+                _ => Some(Box::new(BooleanQuery::from(queries))),
+            }
+        }
+        QueryAst::Boolean(clauses) => {
+            let mut queries: Vec<(Occur, Box<dyn Query>)> = clauses
+                .iter()
+                .filter_map(|(occur, c)| to_query(index, fields, c).map(|q| (*occur, q)))
+                .collect();
+            if queries.is_empty() {
+                return None;
+            }
+            // a query made only of exclusions matches nothing on its own, so give it something
+            // to exclude from
+            if queries.iter().all(|(occur, _)| *occur == Occur::MustNot) {
+                queries.push((Occur::Must, Box::new(AllQuery)));
+            }
+            Some(Box::new(BooleanQuery::from(queries)))
+        }
+    }
+}
+
+fn text_query(index: &Index, field: Field, text: &str) -> Option<Box<dyn Query>> {
+    if let FieldType::HierarchicalFacet = index.schema().get_field_entry(field).field_type() {
+        let facet = Facet::from_path(vec![search::normalize_tag(text)?]);
+        return Some(Box::new(TermQuery::new(
+            Term::from_facet(field, &facet),
+            IndexRecordOption::Basic,
+        )));
+    }
+
+    let tokenizer = index.tokenizer_for_field(field).ok()?;
+    let mut stream = tokenizer.token_stream(text);
+    let mut terms = Vec::new();
+    while let Some(token) = stream.next() {
+        terms.push((token.position, Term::from_field_text(field, &token.text)));
+    }
+
+    match terms.len() {
+        0 => None,
+        1 => Some(Box::new(TermQuery::new(
+            terms.pop().unwrap().1,
+            IndexRecordOption::WithFreqs,
+        ))),
+        // a single word the tokenizer splits up, like "e-mail", is searched as a phrase too
+        _ => Some(Box::new(PhraseQuery::new_with_offset(terms))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, QueryAst};
+    use tantivy::query::Occur;
+
+    const FIELDS: &[&str] = &["title", "tag"];
+
+    fn parsed(text: &str) -> QueryAst {
+        parse(text, FIELDS).unwrap()
+    }
+
+    fn assert_error(text: &str, position: usize, message: &str) {
+        let error = parse(text, FIELDS).unwrap_err();
+        assert_eq!(error.message, message, "{}", text);
+        assert_eq!(error.position, position, "{}", text);
+    }
+
+    fn term(text: &str) -> QueryAst {
+        QueryAst::Term {
+            field: None,
+            text: String::from(text),
+        }
+    }
+
+    fn field_term(field: &str, text: &str) -> QueryAst {
+        QueryAst::Term {
+            field: Some(String::from(field)),
+            text: String::from(text),
+        }
+    }
+
+    fn phrase(field: Option<&str>, text: &str) -> QueryAst {
+        QueryAst::Phrase {
+            field: field.map(String::from),
+            text: String::from(text),
+        }
+    }
+
+    fn all(occur: Occur, children: Vec<QueryAst>) -> QueryAst {
+        QueryAst::Boolean(children.into_iter().map(|c| (occur, c)).collect())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parsed("a OR b AND c"),
+            all(
+                Occur::Should,
+                vec![term("a"), all(Occur::Must, vec![term("b"), term("c")])]
+            )
+        );
+        assert_eq!(
+            parsed("a b OR c"),
+            all(
+                Occur::Should,
+                vec![all(Occur::Should, vec![term("a"), term("b")]), term("c")]
+            )
+        );
+        assert_eq!(
+            parsed("(a OR b) AND c"),
+            all(
+                Occur::Must,
+                vec![all(Occur::Should, vec![term("a"), term("b")]), term("c")]
+            )
+        );
+    }
+
+    #[test]
+    fn plus_and_minus_only_start_clauses() {
+        assert_eq!(
+            parsed("+a -b c"),
+            QueryAst::Boolean(vec![
+                (Occur::Must, term("a")),
+                (Occur::MustNot, term("b")),
+                (Occur::Should, term("c")),
+            ])
+        );
+        assert_eq!(parsed("-a"), all(Occur::MustNot, vec![term("a")]));
+        assert_eq!(parsed("e-mail"), term("e-mail"));
+        assert_eq!(parsed("c++"), term("c++"));
+    }
+
+    #[test]
+    fn quotes_make_phrases() {
+        assert_eq!(parsed("\"hello world\""), phrase(None, "hello world"));
+        assert_eq!(
+            parsed("say\"hi there\""),
+            all(Occur::Should, vec![term("say"), phrase(None, "hi there")])
+        );
+        assert_eq!(
+            parsed("-title:\"to do\""),
+            all(Occur::MustNot, vec![phrase(Some("title"), "to do")])
+        );
+    }
+
+    #[test]
+    fn field_prefixes_apply_to_what_follows_them() {
+        assert_eq!(parsed("tag:work"), field_term("tag", "work"));
+        assert_eq!(
+            parsed("tag:(a OR b) c"),
+            all(
+                Occur::Should,
+                vec![
+                    all(
+                        Occur::Should,
+                        vec![field_term("tag", "a"), field_term("tag", "b")]
+                    ),
+                    term("c"),
+                ]
+            )
+        );
+        // only the name of a field is taken as one, anything else is text
+        assert_eq!(parsed("10:30"), term("10:30"));
+        assert_eq!(parsed("note:x"), term("note:x"));
+        assert_eq!(
+            parsed("todo: milk"),
+            all(Occur::Should, vec![term("todo:"), term("milk")])
+        );
+        assert_eq!(parsed("http://example.com"), term("http://example.com"));
+        assert_error("a tag:", 6, "Unexpected end of query");
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_errors() {
+        assert_error("a (b c", 2, "Unclosed parenthesis");
+        assert_error("a) b", 1, "Unexpected ')'");
+        assert_error("()", 1, "Unexpected ')'");
+        assert_error("((a)", 0, "Unclosed parenthesis");
+    }
+
+    #[test]
+    fn errors_give_their_position_in_characters() {
+        assert_error("a OR", 4, "Unexpected end of query");
+        assert_error("AND a", 0, "Unexpected AND");
+        assert_error("a \"b", 2, "Unterminated phrase");
+        assert_error("café (a", 5, "Unclosed parenthesis");
+        assert_eq!(
+            parse("a OR", FIELDS).unwrap_err().to_string(),
+            "Unexpected end of query at position 4"
+        );
+    }
+}
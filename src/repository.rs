@@ -0,0 +1,531 @@
+//! Where notes are kept. The note database is the only copy of them that matters: the search
+//! index is built from it, and can be thrown away and built again at any time.
+
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    auth::{from_json, migrate_schema, to_json},
+    search::{DocumentId, Note, Revision, TrashedNote},
+};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    NotFound,
+    /// The update was based on an older version of the note, which is given as it is now.
+    Conflict(Box<Note>),
+    DatabaseError(rusqlite::Error),
+    StoreInaccessible,
+}
+
+impl From<rusqlite::Error> for RepositoryError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::DatabaseError(error)
+    }
+}
+
+impl<T> From<PoisonError<T>> for RepositoryError {
+    fn from(_error: PoisonError<T>) -> Self {
+        Self::StoreInaccessible
+    }
+}
+
+/// A note along with who it belongs to, and when it was moved to the trash if it's there.
+#[derive(Debug, Clone)]
+pub struct StoredNote {
+    pub user_id: u64,
+    pub note: Note,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct StoredRevision {
+    pub note_id: DocumentId,
+    pub revision: Revision,
+}
+
+/// Keeps notes, their revisions and the trash. Notes are only ever looked up by id here;
+/// searching and listing them is left to the search index.
+pub trait NoteRepository: Send + Sync {
+    /// Counts the changes made to notes outside the trash, which are the ones the search index
+    /// has to follow. The index records how far through them it is.
+    fn seq(&self) -> Result<u64, RepositoryError>;
+
+    /// Saves a new note, returning the id it was given.
+    fn add(&self, user_id: u64, note: &Note) -> Result<DocumentId, RepositoryError>;
+
+    /// Looks up a note that isn't in the trash.
+    fn get(&self, user_id: u64, id: DocumentId) -> Result<Note, RepositoryError>;
+
+    /// Replaces the note with `note.id`, keeping the old copy as its next revision. The note
+    /// keeps its creation time and its version goes up by one. If `expected_version` is given,
+    /// the update only goes ahead if the note is still at that version. Returns the note as it
+    /// was saved.
+    fn update(
+        &self,
+        user_id: u64,
+        note: Note,
+        expected_version: Option<u64>,
+    ) -> Result<Note, RepositoryError>;
+
+    /// Applies `change` to every note of `user_id`, or of every user if it's `None`, including
+    /// those in the trash. The notes it reports as changed are saved with their version bumped
+    /// but without keeping a revision, and are returned.
+    fn rewrite(
+        &self,
+        user_id: Option<u64>,
+        change: &dyn Fn(&mut StoredNote) -> bool,
+    ) -> Result<Vec<StoredNote>, RepositoryError>;
+
+    /// Moves a note to the trash, returning it.
+    fn trash(
+        &self,
+        user_id: u64,
+        id: DocumentId,
+        deleted_at: DateTime<Utc>,
+    ) -> Result<Note, RepositoryError>;
+
+    /// Takes a note back out of the trash, returning it.
+    fn restore(&self, user_id: u64, id: DocumentId) -> Result<Note, RepositoryError>;
+
+    /// Lists the notes in the user's trash, most recently deleted first.
+    fn list_trash(&self, user_id: u64) -> Result<Vec<TrashedNote>, RepositoryError>;
+
+    /// Deletes every note in the user's trash for good, returning how many there were.
+    fn empty_trash(&self, user_id: u64) -> Result<usize, RepositoryError>;
+
+    /// Deletes every user's notes that were moved to the trash before `cutoff`.
+    fn purge_trash(&self, cutoff: DateTime<Utc>) -> Result<usize, RepositoryError>;
+
+    /// Lists the saved versions of a note, newest first.
+    fn revisions(&self, user_id: u64, id: DocumentId) -> Result<Vec<Revision>, RepositoryError>;
+
+    fn revision(
+        &self,
+        user_id: u64,
+        id: DocumentId,
+        revision: u64,
+    ) -> Result<Revision, RepositoryError>;
+
+    /// Counts the user's notes, not including the trash.
+    fn count(&self, user_id: u64) -> Result<usize, RepositoryError>;
+
+    /// Deletes everything belonging to a user: their notes, revisions and trash.
+    fn delete_user(&self, user_id: u64) -> Result<(), RepositoryError>;
+
+    /// Calls `visit` with every note outside the trash and its user, in order of id.
+    fn for_each_note(&self, visit: &mut dyn FnMut(u64, Note)) -> Result<(), RepositoryError>;
+
+    /// Saves notes and revisions kept somewhere else, keeping their ids. Ones that are already
+    /// here are left alone. Returns the number of notes saved.
+    fn import(
+        &self,
+        notes: &[StoredNote],
+        revisions: &[StoredRevision],
+    ) -> Result<usize, RepositoryError>;
+}
+
+/// Changes to the schema, in order. The database's `user_version` is the number applied so far.
+const SCHEMA_MIGRATIONS: &[&str] = &["CREATE TABLE notes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        tags TEXT NOT NULL DEFAULT '[]',
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        deleted_at INTEGER
+    );
+    CREATE INDEX notes_by_user ON notes (user_id, deleted_at);
+    CREATE TABLE revisions (
+        note_id INTEGER NOT NULL,
+        revision INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        body TEXT NOT NULL,
+        saved_at INTEGER NOT NULL,
+        PRIMARY KEY (note_id, revision)
+    );
+    CREATE TABLE meta (seq INTEGER NOT NULL);
+    INSERT INTO meta (seq) VALUES (0);"];
+const NOTE_COLUMNS: &str =
+    "id, user_id, title, body, tags, created_at, updated_at, version, deleted_at";
+
+/// Notes kept in SQLite. Times are kept to the second, like in the search index.
+pub struct SqliteNoteRepository {
+    db: Mutex<Connection>,
+}
+
+impl SqliteNoteRepository {
+    pub fn open(db_path: &str) -> Result<Self, RepositoryError> {
+        let mut db = Connection::open(db_path)?;
+        migrate_schema(&mut db, SCHEMA_MIGRATIONS)?;
+        Ok(SqliteNoteRepository { db: Mutex::new(db) })
+    }
+}
+
+impl NoteRepository for SqliteNoteRepository {
+    fn seq(&self) -> Result<u64, RepositoryError> {
+        let db = self.db.lock()?;
+        let seq: i64 = db.query_row("SELECT seq FROM meta", params![], |row| row.get(0))?;
+        Ok(seq as u64)
+    }
+
+    fn add(&self, user_id: u64, note: &Note) -> Result<DocumentId, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        tx.execute(
+            "INSERT INTO notes (user_id, title, body, tags, created_at, updated_at, version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                user_id as i64,
+                note.title,
+                note.body,
+                to_json(&note.tags)?,
+                note.created_at.timestamp(),
+                note.updated_at.timestamp(),
+                note.version as i64,
+            ],
+        )?;
+        let id = tx.last_insert_rowid() as DocumentId;
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn get(&self, user_id: u64, id: DocumentId) -> Result<Note, RepositoryError> {
+        let db = self.db.lock()?;
+        find_note(&db, user_id, id, false)
+    }
+
+    fn update(
+        &self,
+        user_id: u64,
+        mut note: Note,
+        expected_version: Option<u64>,
+    ) -> Result<Note, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let existing = find_note(&tx, user_id, note.id, false)?;
+        if expected_version.map_or(false, |v| v != existing.version) {
+            return Err(RepositoryError::Conflict(Box::new(existing)));
+        }
+        note.created_at = existing.created_at;
+        note.version = existing.version + 1;
+
+        tx.execute(
+            "INSERT INTO revisions (note_id, revision, title, body, saved_at)
+             SELECT ?1, COALESCE(MAX(revision), 0) + 1, ?2, ?3, ?4
+             FROM revisions WHERE note_id = ?1",
+            params![
+                existing.id as i64,
+                existing.title,
+                existing.body,
+                existing.updated_at.timestamp(),
+            ],
+        )?;
+        save_note(&tx, &note)?;
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(note)
+    }
+
+    fn rewrite(
+        &self,
+        user_id: Option<u64>,
+        change: &dyn Fn(&mut StoredNote) -> bool,
+    ) -> Result<Vec<StoredNote>, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let notes = {
+            let mut statement = tx.prepare(&format!(
+                "SELECT {} FROM notes WHERE ?1 IS NULL OR user_id = ?1 ORDER BY id",
+                NOTE_COLUMNS
+            ))?;
+            let rows =
+                statement.query_map(params![user_id.map(|id| id as i64)], stored_note_from_row)?;
+            rows.collect::<rusqlite::Result<Vec<StoredNote>>>()?
+        };
+
+        let mut changed = Vec::new();
+        for mut stored in notes {
+            if !change(&mut stored) {
+                continue;
+            }
+            stored.note.version += 1;
+            save_note(&tx, &stored.note)?;
+            changed.push(stored);
+        }
+        if !changed.is_empty() {
+            bump_seq(&tx)?;
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    fn trash(
+        &self,
+        user_id: u64,
+        id: DocumentId,
+        deleted_at: DateTime<Utc>,
+    ) -> Result<Note, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let note = find_note(&tx, user_id, id, false)?;
+        tx.execute(
+            "UPDATE notes SET deleted_at = ?1 WHERE id = ?2",
+            params![deleted_at.timestamp(), id as i64],
+        )?;
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(note)
+    }
+
+    fn restore(&self, user_id: u64, id: DocumentId) -> Result<Note, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let note = find_note(&tx, user_id, id, true)?;
+        tx.execute(
+            "UPDATE notes SET deleted_at = NULL WHERE id = ?1",
+            params![id as i64],
+        )?;
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(note)
+    }
+
+    fn list_trash(&self, user_id: u64) -> Result<Vec<TrashedNote>, RepositoryError> {
+        let db = self.db.lock()?;
+        let mut statement = db.prepare(&format!(
+            "SELECT {} FROM notes WHERE user_id = ?1 AND deleted_at IS NOT NULL
+             ORDER BY deleted_at DESC",
+            NOTE_COLUMNS
+        ))?;
+        let rows = statement.query_map(params![user_id as i64], stored_note_from_row)?;
+        let mut notes = Vec::new();
+        for stored in rows {
+            let stored = stored?;
+            if let Some(deleted_at) = stored.deleted_at {
+                notes.push(TrashedNote {
+                    note: stored.note,
+                    deleted_at,
+                });
+            }
+        }
+        Ok(notes)
+    }
+
+    fn empty_trash(&self, user_id: u64) -> Result<usize, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM revisions WHERE note_id IN
+             (SELECT id FROM notes WHERE user_id = ?1 AND deleted_at IS NOT NULL)",
+            params![user_id as i64],
+        )?;
+        let count = tx.execute(
+            "DELETE FROM notes WHERE user_id = ?1 AND deleted_at IS NOT NULL",
+            params![user_id as i64],
+        )?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    fn purge_trash(&self, cutoff: DateTime<Utc>) -> Result<usize, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM revisions WHERE note_id IN (SELECT id FROM notes WHERE deleted_at < ?1)",
+            params![cutoff.timestamp()],
+        )?;
+        let count = tx.execute(
+            "DELETE FROM notes WHERE deleted_at < ?1",
+            params![cutoff.timestamp()],
+        )?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    fn revisions(&self, user_id: u64, id: DocumentId) -> Result<Vec<Revision>, RepositoryError> {
+        let db = self.db.lock()?;
+        let mut statement = db.prepare(
+            "SELECT r.revision, r.title, r.body, r.saved_at
+             FROM revisions r JOIN notes n ON n.id = r.note_id
+             WHERE r.note_id = ?1 AND n.user_id = ?2
+             ORDER BY r.revision DESC",
+        )?;
+        let rows = statement.query_map(params![id as i64, user_id as i64], revision_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<Revision>>>()?)
+    }
+
+    fn revision(
+        &self,
+        user_id: u64,
+        id: DocumentId,
+        revision: u64,
+    ) -> Result<Revision, RepositoryError> {
+        let db = self.db.lock()?;
+        db.query_row(
+            "SELECT r.revision, r.title, r.body, r.saved_at
+             FROM revisions r JOIN notes n ON n.id = r.note_id
+             WHERE r.note_id = ?1 AND n.user_id = ?2 AND r.revision = ?3",
+            params![id as i64, user_id as i64, revision as i64],
+            revision_from_row,
+        )
+        .optional()?
+        .ok_or(RepositoryError::NotFound)
+    }
+
+    fn count(&self, user_id: u64) -> Result<usize, RepositoryError> {
+        let db = self.db.lock()?;
+        let count: i64 = db.query_row(
+            "SELECT COUNT(*) FROM notes WHERE user_id = ?1 AND deleted_at IS NULL",
+            params![user_id as i64],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn delete_user(&self, user_id: u64) -> Result<(), RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM revisions WHERE note_id IN (SELECT id FROM notes WHERE user_id = ?1)",
+            params![user_id as i64],
+        )?;
+        tx.execute(
+            "DELETE FROM notes WHERE user_id = ?1",
+            params![user_id as i64],
+        )?;
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn for_each_note(&self, visit: &mut dyn FnMut(u64, Note)) -> Result<(), RepositoryError> {
+        let db = self.db.lock()?;
+        let mut statement = db.prepare(&format!(
+            "SELECT {} FROM notes WHERE deleted_at IS NULL ORDER BY id",
+            NOTE_COLUMNS
+        ))?;
+        for stored in statement.query_map(params![], stored_note_from_row)? {
+            let stored = stored?;
+            visit(stored.user_id, stored.note);
+        }
+        Ok(())
+    }
+
+    fn import(
+        &self,
+        notes: &[StoredNote],
+        revisions: &[StoredRevision],
+    ) -> Result<usize, RepositoryError> {
+        let mut db = self.db.lock()?;
+        let tx = db.transaction()?;
+        let mut count = 0;
+        for stored in notes {
+            let note = &stored.note;
+            count += tx.execute(
+                "INSERT OR IGNORE INTO notes (id, user_id, title, body, tags, created_at,
+                 updated_at, version, deleted_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    note.id as i64,
+                    stored.user_id as i64,
+                    note.title,
+                    note.body,
+                    to_json(&note.tags)?,
+                    note.created_at.timestamp(),
+                    note.updated_at.timestamp(),
+                    note.version as i64,
+                    stored.deleted_at.map(|d| d.timestamp()),
+                ],
+            )?;
+        }
+        for stored in revisions {
+            let revision = &stored.revision;
+            tx.execute(
+                "INSERT OR IGNORE INTO revisions (note_id, revision, title, body, saved_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    stored.note_id as i64,
+                    revision.revision as i64,
+                    revision.title,
+                    revision.body,
+                    revision.saved_at.timestamp(),
+                ],
+            )?;
+        }
+        bump_seq(&tx)?;
+        tx.commit()?;
+        Ok(count)
+    }
+}
+
+fn bump_seq(db: &Connection) -> rusqlite::Result<()> {
+    db.execute("UPDATE meta SET seq = seq + 1", params![])?;
+    Ok(())
+}
+
+fn find_note(
+    db: &Connection,
+    user_id: u64,
+    id: DocumentId,
+    trashed: bool,
+) -> Result<Note, RepositoryError> {
+    db.query_row(
+        &format!(
+            "SELECT {} FROM notes WHERE id = ?1 AND user_id = ?2
+             AND (deleted_at IS NOT NULL) = ?3",
+            NOTE_COLUMNS
+        ),
+        params![id as i64, user_id as i64, trashed],
+        stored_note_from_row,
+    )
+    .optional()?
+    .map(|stored| stored.note)
+    .ok_or(RepositoryError::NotFound)
+}
+
+fn save_note(db: &Connection, note: &Note) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE notes SET title = ?1, body = ?2, tags = ?3, updated_at = ?4, version = ?5
+         WHERE id = ?6",
+        params![
+            note.title,
+            note.body,
+            to_json(&note.tags)?,
+            note.updated_at.timestamp(),
+            note.version as i64,
+            note.id as i64,
+        ],
+    )?;
+    Ok(())
+}
+
+fn stored_note_from_row(row: &Row) -> rusqlite::Result<StoredNote> {
+    let tags: String = row.get(4)?;
+    let deleted_at: Option<i64> = row.get(8)?;
+    Ok(StoredNote {
+        user_id: row.get::<_, i64>(1)? as u64,
+        note: Note {
+            id: row.get::<_, i64>(0)? as DocumentId,
+            title: row.get(2)?,
+            body: row.get(3)?,
+            tags: from_json(4, &tags)?,
+            created_at: Utc.timestamp(row.get(5)?, 0),
+            updated_at: Utc.timestamp(row.get(6)?, 0),
+            version: row.get::<_, i64>(7)? as u64,
+        },
+        deleted_at: deleted_at.map(|d| Utc.timestamp(d, 0)),
+    })
+}
+
+fn revision_from_row(row: &Row) -> rusqlite::Result<Revision> {
+    Ok(Revision {
+        revision: row.get::<_, i64>(0)? as u64,
+        title: row.get(1)?,
+        body: row.get(2)?,
+        saved_at: Utc.timestamp(row.get(3)?, 0),
+    })
+}
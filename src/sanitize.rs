@@ -0,0 +1,160 @@
+use scraper::{ElementRef, Html, Node};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+
+/// Tags that are dropped along with everything inside them, rather than just being unwrapped.
+const DROP_CONTENT_TAGS: &[&str] = &[
+    "script", "style", "iframe", "object", "embed", "noscript", "template", "textarea", "title",
+];
+const VOID_TAGS: &[&str] = &["br", "hr", "img", "wbr"];
+const URL_ATTRIBUTES: &[&str] = &["href", "src"];
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// The markup removed from a note body, by name.
+#[derive(Debug, Default, Serialize)]
+pub struct StrippedMarkup {
+    pub tags: Vec<String>,
+    pub attributes: Vec<String>,
+}
+
+impl StrippedMarkup {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.attributes.is_empty()
+    }
+}
+
+/// Cleans note bodies down to an allow-list of tags and attributes. Tags that aren't allowed are
+/// unwrapped so their text is kept, except for those whose content is never meant to be seen,
+/// like scripts. Link attributes must also use a safe URL scheme.
+pub struct Sanitizer {
+    tags: HashSet<String>,
+    attributes: HashSet<String>,
+}
+
+impl Sanitizer {
+    pub fn new<T, A>(tags: T, attributes: A) -> Self
+    where
+        T: IntoIterator,
+        T::Item: AsRef<str>,
+        A: IntoIterator,
+        A::Item: AsRef<str>,
+    {
+        Sanitizer {
+            tags: tags.into_iter().map(|t| t.as_ref().to_lowercase()).collect(),
+            attributes: attributes
+                .into_iter()
+                .map(|a| a.as_ref().to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn clean(&self, html: &str) -> (String, StrippedMarkup) {
+        let fragment = Html::parse_fragment(html);
+        let mut out = String::with_capacity(html.len());
+        let mut stripped_tags = BTreeSet::new();
+        let mut stripped_attributes = BTreeSet::new();
+
+        self.write_children(
+            fragment.root_element(),
+            &mut out,
+            &mut stripped_tags,
+            &mut stripped_attributes,
+        );
+
+        let stripped = StrippedMarkup {
+            tags: stripped_tags.into_iter().collect(),
+            attributes: stripped_attributes.into_iter().collect(),
+        };
+        (out, stripped)
+    }
+
+    fn write_children(
+        &self,
+        element: ElementRef,
+        out: &mut String,
+        stripped_tags: &mut BTreeSet<String>,
+        stripped_attributes: &mut BTreeSet<String>,
+    ) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => escape(out, text, false),
+                Node::Element(_) => {
+                    let child = ElementRef::wrap(child).unwrap();
+                    self.write_element(child, out, stripped_tags, stripped_attributes);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn write_element(
+        &self,
+        element: ElementRef,
+        out: &mut String,
+        stripped_tags: &mut BTreeSet<String>,
+        stripped_attributes: &mut BTreeSet<String>,
+    ) {
+        let name = element.value().name();
+        if DROP_CONTENT_TAGS.contains(&name) {
+            stripped_tags.insert(String::from(name));
+            return;
+        }
+        if !self.tags.contains(name) {
+            stripped_tags.insert(String::from(name));
+            self.write_children(element, out, stripped_tags, stripped_attributes);
+            return;
+        }
+
+        out.push('<');
+        out.push_str(name);
+        for (attribute, value) in element.value().attrs() {
+            if !self.attributes.contains(attribute)
+                || (URL_ATTRIBUTES.contains(&attribute) && !is_safe_url(value))
+            {
+                stripped_attributes.insert(String::from(attribute));
+                continue;
+            }
+            out.push(' ');
+            out.push_str(attribute);
+            out.push_str("=\"");
+            escape(out, value, true);
+            out.push('"');
+        }
+        out.push('>');
+
+        if VOID_TAGS.contains(&name) {
+            return;
+        }
+        self.write_children(element, out, stripped_tags, stripped_attributes);
+        out.push_str("</");
+        out.push_str(name);
+        out.push('>');
+    }
+}
+
+/// Relative URLs are fine; absolute ones need one of the allowed schemes.
+fn is_safe_url(url: &str) -> bool {
+    // browsers ignore whitespace and control characters inside the scheme
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect();
+    match url.find(':') {
+        Some(colon) if !url[..colon].contains(|c| c == '/' || c == '?' || c == '#') => {
+            URL_SCHEMES.contains(&url[..colon].to_lowercase().as_str())
+        }
+        _ => true,
+    }
+}
+
+fn escape(out: &mut String, text: &str, attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
@@ -1,17 +1,35 @@
-use atomic_counter::{AtomicCounter, RelaxedCounter};
+use chrono::{DateTime, Utc};
 use scraper::Html;
 use serde::{Deserialize, Serialize};
-use std::{fs, sync::Mutex};
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Bound,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
 use tantivy::{
-    collector::TopDocs,
+    collector::{Count, FacetCollector, TopDocs},
     directory::MmapDirectory,
+    fastfield::FastFieldReader,
     query::{AllQuery, BooleanQuery, Occur, Query, RangeQuery, TermQuery},
     schema::*,
     tokenizer::{Language, LowerCaser, RemoveLongFilter, Stemmer, Token, TokenStream, Tokenizer},
-    DocAddress, Error, Index, IndexReader, IndexWriter, Term,
+    DocAddress, DocId, Error, Index, IndexReader, IndexWriter, Score, SegmentReader, Term,
 };
 
-use crate::constants;
+use crate::{
+    constants,
+    highlight::{self, TagSpans},
+    query::{self, QueryParseError},
+    repository::{NoteRepository, RepositoryError, StoredNote, StoredRevision},
+    wal::{self, NoteChange},
+};
 
 #[derive(Clone)]
 struct HtmlTokenizer;
@@ -27,7 +45,7 @@ impl<'a> Tokenizer<'a> for HtmlTokenizer {
 }
 
 struct HtmlTokenStream {
-    text_tokens: Vec<String>,
+    text_tokens: Vec<(String, usize, usize)>,
     index: usize,
     token: Token,
 }
@@ -35,12 +53,30 @@ struct HtmlTokenStream {
 impl HtmlTokenStream {
     fn new(raw_text: &str) -> HtmlTokenStream {
         let fragment = Html::parse_fragment(raw_text);
-        let text_tokens = fragment
+        let words: Vec<String> = fragment
             .root_element()
             .text()
             .flat_map(|s| s.split(|c: char| !c.is_alphanumeric()))
-            .filter(|s| s.len() > 0)
-            .map(|s| String::from(s))
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+
+        // HTML parsing loses the byte offsets of the text, so find each word again in the raw
+        // source, skipping over anything that's part of a tag.
+        let tags = TagSpans::new(raw_text);
+        let mut cursor = 0;
+        let text_tokens = words
+            .into_iter()
+            .map(|word| {
+                let (from, to) = match find_word(raw_text, &tags, &word, cursor) {
+                    Some(from) => (from, from + word.len()),
+                    // the word was mangled by an HTML entity; it can't be highlighted, but it
+                    // still gets indexed
+                    None => (cursor, cursor),
+                };
+                cursor = to;
+                (word, from, to)
+            })
             .collect();
 
         HtmlTokenStream {
@@ -51,6 +87,24 @@ impl HtmlTokenStream {
     }
 }
 
+fn find_word(text: &str, tags: &TagSpans, word: &str, start: usize) -> Option<usize> {
+    let is_boundary = |c: Option<char>| c.map(|c| !c.is_alphanumeric()).unwrap_or(true);
+
+    let mut from = start;
+    while let Some(i) = text[from..].find(word) {
+        let pos = from + i;
+        let end = pos + word.len();
+        if !tags.contains(pos)
+            && is_boundary(text[..pos].chars().next_back())
+            && is_boundary(text[end..].chars().next())
+        {
+            return Some(pos);
+        }
+        from = pos + text[pos..].chars().next().unwrap().len_utf8();
+    }
+    None
+}
+
 impl TokenStream for HtmlTokenStream {
     fn advance(&mut self) -> bool {
         self.token.text.clear();
@@ -60,11 +114,10 @@ impl TokenStream for HtmlTokenStream {
             return false;
         }
 
-        // set these to zero, since HTML parsing changes the number and offsets of bytes
-        // (converting HTML entities and the like).
-        self.token.offset_from = 0;
-        self.token.offset_to = 0;
-        self.token.text.push_str(&self.text_tokens[self.index]);
+        let (text, offset_from, offset_to) = &self.text_tokens[self.index];
+        self.token.offset_from = *offset_from;
+        self.token.offset_to = *offset_to;
+        self.token.text.push_str(text);
 
         self.index += 1;
         true
@@ -80,38 +133,352 @@ impl TokenStream for HtmlTokenStream {
 }
 
 pub type DocumentId = usize;
+pub use tantivy::Opstamp;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     pub id: DocumentId,
     pub title: String,
     pub body: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Goes up by one with every change to the note, so that an update can tell whether it was
+    /// based on the latest copy.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Note {
+    pub fn new(title: String, body: String, tags: &[String]) -> Self {
+        let now = Utc::now();
+        let mut normalized: Vec<String> = tags.iter().filter_map(|t| normalize_tag(t)).collect();
+        normalized.sort();
+        normalized.dedup();
+        Note {
+            id: 0,
+            title,
+            body,
+            tags: normalized,
+            created_at: now,
+            updated_at: now,
+            version: 0,
+        }
+    }
+}
+
+/// Tags are compared case-insensitively and without surrounding whitespace; empty tags are
+/// dropped.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    if tag.is_empty() || tag.chars().count() > constants::MAX_TAG_LENGTH {
+        None
+    } else {
+        Some(tag)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64,
+}
+
+/// An earlier version of a note, saved whenever the note is updated. Revisions are numbered from
+/// 1 upwards per note.
+#[derive(Debug, Serialize)]
+pub struct Revision {
+    pub revision: u64,
+    pub title: String,
+    pub body: String,
+    pub saved_at: DateTime<Utc>,
+}
+
+/// A note in the trash, which is purged for good once it has been there for the retention period.
+#[derive(Debug, Serialize)]
+pub struct TrashedNote {
+    #[serde(flatten)]
+    pub note: Note,
+    pub deleted_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub note: Note,
+    pub score: f32,
+    pub snippets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Id,
+    Title,
+    Created,
+    Updated,
+}
+
+impl SortField {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "id" => Some(SortField::Id),
+            "title" => Some(SortField::Title),
+            "created" => Some(SortField::Created),
+            "updated" => Some(SortField::Updated),
+            _ => None,
+        }
+    }
+
+    fn field_name(self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Title => "title_sort",
+            SortField::Created => "created_at",
+            SortField::Updated => "updated_at",
+        }
+    }
+}
+
+/// Reads a note's sort key from a u64 or date fast field. Dates are mapped onto u64 so that
+/// they keep their order.
+enum SortKeys {
+    Int(FastFieldReader<u64>),
+    Date(FastFieldReader<tantivy::DateTime>),
+}
+
+impl SortKeys {
+    fn open(segment_reader: &SegmentReader, field: Field) -> Self {
+        let fast_fields = segment_reader.fast_fields();
+        match fast_fields.date(field) {
+            Some(dates) => SortKeys::Date(dates),
+            None => SortKeys::Int(fast_fields.u64(field).expect("not a sortable field")),
+        }
+    }
+
+    fn get(&self, doc: DocId) -> u64 {
+        match self {
+            SortKeys::Int(values) => values.get(doc),
+            SortKeys::Date(dates) => (dates.get(doc).timestamp() as u64) ^ (1 << 63),
+        }
+    }
+}
+
+/// Limits notes to those created strictly between two instants.
+#[derive(Debug, Clone, Default)]
+pub struct DateRange {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+}
+
+impl DateRange {
+    fn query(&self, field: Field) -> Option<Box<dyn Query>> {
+        if self.after.is_none() && self.before.is_none() {
+            return None;
+        }
+        let bound = |date: &Option<DateTime<Utc>>| match date {
+            Some(date) => Bound::Excluded(Term::from_field_date(field, date)),
+            None => Bound::Unbounded,
+        };
+        Some(Box::new(RangeQuery::new_term_bounds(
+            field,
+            Type::Date,
+            &bound(&self.after),
+            &bound(&self.before),
+        )))
+    }
+}
+
+/// The position of the last note on a page, for fetching the page after it. A cursor is only
+/// meaningful with the sort order that produced it. Sorting by title also needs the whole
+/// lowercased title, since the key only holds the start of it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NoteCursor {
+    key: u64,
+    title: Option<String>,
+    id: u64,
+}
+
+impl NoteCursor {
+    pub fn encode(&self) -> String {
+        let cursor = match &self.title {
+            Some(title) => format!("{}:{}:{}", self.key, self.id, title),
+            None => format!("{}:{}", self.key, self.id),
+        };
+        base64::encode_config(&cursor, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let mut parts = decoded.splitn(3, ':');
+        Some(NoteCursor {
+            key: parts.next()?.parse().ok()?,
+            id: parts.next()?.parse().ok()?,
+            title: parts.next().map(String::from),
+        })
+    }
+
+    /// Whether a note at `self` comes after one at `other`.
+    fn is_after(&self, other: &NoteCursor, descending: bool) -> bool {
+        if descending {
+            self < other
+        } else {
+            self > other
+        }
+    }
+}
+
+pub struct ListOptions {
+    pub sort: SortField,
+    pub descending: bool,
+    pub limit: usize,
+    pub after: Option<NoteCursor>,
+    pub created: DateRange,
+}
+
+pub struct SearchOptions {
+    /// Orders results by a field instead of by relevance.
+    pub sort: Option<SortField>,
+    pub descending: bool,
+    pub count: usize,
+    pub created: DateRange,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotePage {
+    pub notes: Vec<Note>,
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    InvalidQuery(QueryParseError),
+    IndexError(Error),
+}
+
+impl From<QueryParseError> for SearchError {
+    fn from(error: QueryParseError) -> Self {
+        Self::InvalidQuery(error)
+    }
+}
+
+impl From<Error> for SearchError {
+    fn from(error: Error) -> Self {
+        Self::IndexError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    NotFound,
+    /// The update was based on an older version of the note, which is given as it is now.
+    Conflict(Box<Note>),
+    IndexError(Error),
+}
+
+impl From<Error> for UpdateError {
+    fn from(error: Error) -> Self {
+        Self::IndexError(error)
+    }
+}
+
+impl From<RepositoryError> for UpdateError {
+    fn from(error: RepositoryError) -> Self {
+        match error {
+            RepositoryError::NotFound => Self::NotFound,
+            RepositoryError::Conflict(note) => Self::Conflict(note),
+            error => Self::IndexError(error.into()),
+        }
+    }
+}
+
+impl From<RepositoryError> for Error {
+    fn from(error: RepositoryError) -> Self {
+        match error {
+            RepositoryError::NotFound => Error::InvalidArgument(String::from("Note not found")),
+            error => Error::SystemError(format!("{:?}", error)),
+        }
+    }
 }
 
+/// When queued writes are committed, which is when they become searchable.
+#[derive(Debug, Clone, Copy)]
+pub struct CommitPolicy {
+    /// Commit as soon as this many writes are waiting.
+    pub max_pending: usize,
+    /// Commit whatever is waiting at least this often.
+    pub max_delay: Duration,
+}
+
+/// The index writer, along with how many writes it has queued since it last committed.
+struct Writer {
+    index: IndexWriter,
+    pending_ops: usize,
+}
+
+/// Cloning a `NoteStore` gives another handle to the same notes and index.
+///
+/// Notes are kept in a `NoteRepository`, and the index only holds a copy of the ones outside the
+/// trash, for searching and listing them. Every write is saved to the repository before it
+/// returns, but is only queued in the index writer and committed in batches by a background
+/// thread. Each write returns the opstamp of its last operation, which `wait_for` takes to make
+/// sure the write can be searched. Notes read back by id come from the repository, so writes
+/// are seen there straight away. Changes to many notes at once are committed before they
+/// return.
+///
+/// Each commit records how far through the repository's changes it is. An index that's behind
+/// when it's opened, because the server stopped before it committed or because the index was
+/// deleted, is built again from the repository.
+#[derive(Clone)]
 pub struct NoteStore {
     index: Index,
     reader: IndexReader,
-    writer: Mutex<IndexWriter>,
-    id_counter: RelaxedCounter,
+    repository: Arc<dyn NoteRepository>,
+    writer: Arc<Mutex<Writer>>,
+    /// Signalled when enough writes are waiting that they should be committed now.
+    commit_ready: Arc<Condvar>,
+    /// The opstamp of the last commit the reader has picked up. Writes with an opstamp below
+    /// it can be searched.
+    searchable: Arc<AtomicU64>,
+    commit_policy: CommitPolicy,
 }
 
 impl NoteStore {
-    pub fn new(index_dir: String) -> tantivy::Result<Self> {
+    pub fn new(
+        index_dir: String,
+        repository: Arc<dyn NoteRepository>,
+        commit_policy: CommitPolicy,
+    ) -> tantivy::Result<Self> {
         let mut builder = Schema::builder();
 
         let text_options = TextOptions::default()
-            .set_indexing_options(TextFieldIndexing::default().set_tokenizer("en_html"))
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("en_html")
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            )
             .set_stored();
 
         builder.add_u64_field("id", STORED | INDEXED | FAST);
         builder.add_u64_field("user_id", STORED | INDEXED | FAST);
         builder.add_text_field("title", text_options.clone());
-        builder.add_text_field("body", text_options.clone());
+        builder.add_text_field("body", text_options);
+        builder.add_u64_field("title_sort", FAST);
+        builder.add_date_field("created_at", STORED | INDEXED | FAST);
+        builder.add_date_field("updated_at", STORED | INDEXED | FAST);
+        builder.add_facet_field("tags");
+        builder.add_u64_field("version", STORED);
+        let schema = builder.build();
 
         fs::create_dir_all(&index_dir)?;
+        if is_legacy_index(&index_dir, &schema)? {
+            migrate_legacy_index(&index_dir, &*repository)?;
+            fs::create_dir_all(&index_dir)?;
+        }
 
         let index_dir = MmapDirectory::open(&index_dir)?;
-        let index = Index::open_or_create(index_dir, builder.build())?;
+        let index = Index::open_or_create(index_dir, schema)?;
 
         let en_html = HtmlTokenizer
             .filter(RemoveLongFilter::limit(40))
@@ -121,78 +488,253 @@ impl NoteStore {
 
         let reader = index.reader()?;
         let writer = index.writer(constants::INDEXER_HEAP_SIZE)?;
+        let committed = index.load_metas()?.payload;
 
         let store = NoteStore {
             index,
             reader,
-            writer: Mutex::new(writer),
-            id_counter: RelaxedCounter::new(0),
+            repository,
+            searchable: Arc::new(AtomicU64::new(writer.commit_opstamp())),
+            writer: Arc::new(Mutex::new(Writer {
+                index: writer,
+                pending_ops: 0,
+            })),
+            commit_ready: Arc::new(Condvar::new()),
+            commit_policy,
         };
 
-        store.id_counter.add(store.next_id()?);
+        if committed != Some(store.payload()?) {
+            println!("Building the search index from the note database");
+            let count = store.rebuild_index()?;
+            println!("Indexed {} notes", count);
+        }
+        store.commit_in_background();
 
         Ok(store)
     }
 
-    pub fn add_note(&self, user_id: u64, note: Note) -> tantivy::Result<DocumentId> {
-        let schema = self.index.schema();
-        let id_field = schema.get_field("id").unwrap();
-        let title_field = schema.get_field("title").unwrap();
-        let body_field = schema.get_field("body").unwrap();
-        let user_id_field = schema.get_field("user_id").unwrap();
+    /// Saves a new note, returning its id and the opstamp of the write.
+    pub fn add_note(&self, user_id: u64, mut note: Note) -> tantivy::Result<(DocumentId, Opstamp)> {
+        let now = Utc::now();
+        note.created_at = now;
+        note.updated_at = now;
+        note.version = 1;
 
-        let calculated_id = self.id_counter.inc();
+        // the index has to be given changes in the order the repository made them
         let mut writer = self.writer.lock()?;
+        note.id = self.repository.add(user_id, &note)?;
+        let id = note.id;
 
-        writer.add_document(doc!(
-                id_field => calculated_id as u64,
-                title_field => note.title,
-                body_field => note.body,
-                user_id_field => user_id,
-        ));
-        writer.commit()?;
-
-        Ok(calculated_id)
+        let opstamp = self.reindex(&mut writer, user_id, id, Some(note));
+        Ok((id, opstamp))
     }
 
     pub fn get_note(&self, user_id: u64, id: DocumentId) -> tantivy::Result<Note> {
-        let (_, doc) = self.get_note_doc(user_id, id)?;
-        Ok(self.load_note(doc))
+        Ok(self.repository.get(user_id, id)?)
+    }
+
+    /// Waits until the write with `opstamp` can be searched, committing it now if it hasn't
+    /// been yet.
+    pub fn wait_for(&self, opstamp: Opstamp) -> tantivy::Result<()> {
+        if opstamp < self.searchable.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock()?;
+        if opstamp < self.searchable.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.commit(&mut writer)
     }
 
     pub fn search_notes(
         &self,
         user_id: u64,
         query_text: &str,
-        result_count: usize,
-    ) -> tantivy::Result<Vec<Note>> {
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>, SearchError> {
         let schema = self.index.schema();
         let title_field = schema.get_field("title").unwrap();
         let body_field = schema.get_field("body").unwrap();
-        let user_id_field = schema.get_field("user_id").unwrap();
-
-        let user_query: Box<dyn Query> = Box::new(RangeQuery::new_u64(
-            user_id_field,
-            user_id as u64..(user_id + 1) as u64,
-        ));
+        let tags_field = schema.get_field("tags").unwrap();
+        let created_field = schema.get_field("created_at").unwrap();
 
-        let search_query =
-            build_search_query(&self.index, vec![title_field, body_field], query_text);
+        let user_query = self.user_query(user_id);
 
-        let compound_query = BooleanQuery::from(vec![
-            (Occur::Must, user_query),
-            (Occur::Should, search_query),
-        ]);
+        let search_query = query::build_query(
+            &self.index,
+            &[title_field, body_field],
+            &[("title", title_field), ("body", body_field), ("tag", tags_field)],
+            query_text,
+        )?;
 
         let reader = &self.reader;
         let searcher = reader.searcher();
-        let fruit = searcher.search(&compound_query, &TopDocs::with_limit(result_count))?;
+        let terms = highlight::query_terms(&searcher, &*search_query, body_field);
 
-        Ok(fruit
+        let mut clauses = vec![(Occur::Must, user_query), (Occur::Must, search_query)];
+        if let Some(range_query) = options.created.query(created_field) {
+            clauses.push((Occur::Must, range_query));
+        }
+        let compound_query = BooleanQuery::from(clauses);
+
+        let fruit: Vec<(Score, DocAddress)> = match options.sort {
+            None => searcher.search(&compound_query, &TopDocs::with_limit(options.count))?,
+            Some(sort) => {
+                let key_field = schema.get_field(sort.field_name()).unwrap();
+                let descending = options.descending;
+                let top_notes = TopDocs::with_limit(options.count).tweak_score(
+                    move |segment_reader: &SegmentReader| {
+                        let keys = SortKeys::open(segment_reader, key_field);
+                        move |doc: DocId, score: Score| {
+                            let key = keys.get(doc);
+                            (if descending { key } else { u64::max_value() - key }, score)
+                        }
+                    },
+                );
+                searcher
+                    .search(&compound_query, &top_notes)?
+                    .into_iter()
+                    .map(|((_, score), addr)| (score, addr))
+                    .collect()
+            }
+        };
+
+        let mut hits: Vec<SearchHit> = fruit
             .iter()
-            .filter(|(score, _)| *score > 1.)
-            .map(|(_, addr)| self.load_note(searcher.doc(*addr).unwrap()))
-            .collect())
+            .map(|(score, addr)| {
+                let note = self.load_note(searcher.doc(*addr).unwrap());
+                let snippets = highlight::snippets(
+                    &self.index,
+                    body_field,
+                    &note.body,
+                    &terms,
+                    constants::SEARCH_SNIPPET_COUNT,
+                );
+                SearchHit {
+                    note,
+                    score: *score,
+                    snippets,
+                }
+            })
+            .collect();
+        if options.sort == Some(SortField::Title) {
+            order_by_full_title(&mut hits, |hit| &hit.note, options.descending);
+        }
+        Ok(hits)
+    }
+
+    pub fn list_notes(&self, user_id: u64, options: &ListOptions) -> tantivy::Result<NotePage> {
+        let schema = self.index.schema();
+        let id_field = schema.get_field("id").unwrap();
+        let key_field = schema.get_field(options.sort.field_name()).unwrap();
+        let created_field = schema.get_field("created_at").unwrap();
+
+        let mut clauses = vec![(Occur::Must, self.user_query(user_id))];
+        if let Some(range_query) = options.created.query(created_field) {
+            clauses.push((Occur::Must, range_query));
+        }
+        let compound_query = BooleanQuery::from(clauses);
+
+        let descending = options.descending;
+        let by_title = options.sort == SortField::Title;
+        let after = options.after.as_ref().map(|after| (after.key, after.id));
+        let searcher = self.reader.searcher();
+        let mut fetch = options.limit + 1;
+        loop {
+            // Notes are ranked by their sort key straight from the fast fields, so only a page's
+            // worth of documents is held, unless titles that share a key run past the end of the
+            // page. Notes at or before the cursor rank as `None` and are dropped afterwards.
+            let top_notes =
+                TopDocs::with_limit(fetch).custom_score(move |segment_reader: &SegmentReader| {
+                    let ids = segment_reader.fast_fields().u64(id_field).unwrap();
+                    let keys = SortKeys::open(segment_reader, key_field);
+                    move |doc: DocId| {
+                        let (key, id) = (keys.get(doc), ids.get(doc));
+                        let is_after = match after {
+                            None => true,
+                            // the titles are only compared once the notes are loaded, so every
+                            // note that shares the cursor's key is kept until then
+                            Some(after) if by_title && descending => key <= after.0,
+                            Some(after) if by_title => key >= after.0,
+                            Some(after) if descending => (key, id) < after,
+                            Some(after) => (key, id) > after,
+                        };
+                        if !is_after {
+                            None
+                        } else if descending {
+                            Some((key, id))
+                        } else {
+                            Some((u64::max_value() - key, u64::max_value() - id))
+                        }
+                    }
+                });
+            let (total, fruit) = searcher.search(&compound_query, &(Count, top_notes))?;
+
+            let mut notes = Vec::new();
+            let mut fetched = 0;
+            let mut last_key = None;
+            for (rank, addr) in fruit.into_iter() {
+                let (key, id) = match rank {
+                    Some((key, id)) if descending => (key, id),
+                    Some((key, id)) => (u64::max_value() - key, u64::max_value() - id),
+                    None => break,
+                };
+                fetched += 1;
+                last_key = Some(key);
+                let note = self.load_note(searcher.doc(addr)?);
+                let cursor = NoteCursor {
+                    key,
+                    title: if by_title {
+                        Some(note.title.trim().to_lowercase())
+                    } else {
+                        None
+                    },
+                    id,
+                };
+                match &options.after {
+                    Some(after) if !cursor.is_after(after, descending) => {}
+                    _ => notes.push((cursor, note)),
+                }
+            }
+            // whether there are notes after the ones fetched
+            let more = fetched == fetch;
+            if by_title {
+                notes.sort_by(|(a, _), (b, _)| if descending { b.cmp(a) } else { a.cmp(b) });
+                // Notes that weren't fetched rank at or after the last key fetched, and may come
+                // between the notes with that key once their titles are compared. Unless the
+                // page ends before that key, fetch more until it does.
+                let page_end = options
+                    .limit
+                    .checked_sub(1)
+                    .and_then(|last| notes.get(last));
+                let page_is_settled = match (page_end, last_key) {
+                    (Some((end, _)), Some(last_key)) => {
+                        if descending {
+                            end.key > last_key
+                        } else {
+                            end.key < last_key
+                        }
+                    }
+                    _ => false,
+                };
+                if more && !page_is_settled && options.limit > 0 {
+                    fetch *= 2;
+                    continue;
+                }
+            }
+
+            let next_cursor = if notes.len() > options.limit && options.limit > 0 {
+                Some(notes[options.limit - 1].0.encode())
+            } else {
+                None
+            };
+            notes.truncate(options.limit);
+            return Ok(NotePage {
+                notes: notes.into_iter().map(|(_, note)| note).collect(),
+                total,
+                next_cursor,
+            });
+        }
     }
 
     pub fn search_similar(
@@ -201,18 +743,13 @@ impl NoteStore {
         note_id: DocumentId,
         result_count: usize,
     ) -> tantivy::Result<Vec<Note>> {
-        let (_, doc) = self.get_note_doc(user_id, note_id)?;
-        let note = self.load_note(doc);
+        let note = self.repository.get(user_id, note_id)?;
 
         let schema = self.index.schema();
         let title_field = schema.get_field("title").unwrap();
         let body_field = schema.get_field("body").unwrap();
-        let user_id_field = schema.get_field("user_id").unwrap();
 
-        let user_query: Box<dyn Query> = Box::new(RangeQuery::new_u64(
-            user_id_field,
-            user_id as u64..(user_id + 1) as u64,
-        ));
+        let user_query = self.user_query(user_id);
 
         let title_query =
             build_search_query(&self.index, vec![title_field, body_field], &note.title);
@@ -236,116 +773,524 @@ impl NoteStore {
             .collect())
     }
 
+    /// Replaces a note, keeping the old copy as a revision. If `expected_version` is given, the
+    /// update only goes ahead if the note is still at that version. Returns the opstamp of the
+    /// write.
     pub fn update_note(
         &self,
         user_id: u64,
         id: DocumentId,
-        note: Note,
-    ) -> tantivy::Result<DocumentId> {
-        let schema = self.index.schema();
-        let id_field = schema.get_field("id").unwrap();
-        let title_field = schema.get_field("title").unwrap();
-        let body_field = schema.get_field("body").unwrap();
-        let user_id_field = schema.get_field("user_id").unwrap();
+        mut note: Note,
+        expected_version: Option<u64>,
+    ) -> Result<Opstamp, UpdateError> {
+        note.id = id;
+        note.updated_at = Utc::now();
 
-        if let Err(_) = self.get_note_doc(user_id, id) {
-            return Err(Error::InvalidArgument(format!("{}, {}", user_id, id)));
-        }
+        let mut writer = self.writer.lock().map_err(Error::from)?;
+        let note = self.repository.update(user_id, note, expected_version)?;
+        Ok(self.reindex(&mut writer, user_id, id, Some(note)))
+    }
 
+    /// Applies `change` to every note of every user, including those in the trash, and saves
+    /// the notes it reports as changed. Returns the number of notes saved.
+    pub fn rewrite_notes<F>(&self, change: F) -> tantivy::Result<usize>
+    where
+        F: Fn(&mut Note) -> bool,
+    {
         let mut writer = self.writer.lock()?;
-        writer.delete_term(Term::from_field_u64(id_field, id as u64));
-        writer.add_document(doc!(
-                id_field => id as u64,
-                title_field => note.title,
-                body_field => note.body,
-                user_id_field => user_id,
-        ));
-        writer.commit()?;
-        Ok(id)
+        let changed = self
+            .repository
+            .rewrite(None, &|stored| change(&mut stored.note))?;
+        self.reindex_changed(&mut writer, changed)
     }
 
-    pub fn delete_note(&self, user_id: u64, id: DocumentId) -> tantivy::Result<Note> {
-        let schema = self.index.schema();
-        let id_field = schema.get_field("id").unwrap();
-
-        let (_, doc) = match self.get_note_doc(user_id, id) {
-            Ok(pair) => pair,
-            Err(_) => return Err(Error::InvalidArgument(format!("{}, {}", user_id, id))),
-        };
-        let note = self.load_note(doc);
-        let mut writer = self.writer.lock()?;
-        writer.delete_term(Term::from_field_u64(id_field, note.id as u64));
-        writer.commit()?;
-        Ok(note)
+    /// Lists the saved versions of a note, newest first.
+    pub fn list_revisions(&self, user_id: u64, id: DocumentId) -> tantivy::Result<Vec<Revision>> {
+        // make sure the note itself still exists and belongs to the user
+        self.repository.get(user_id, id)?;
+        Ok(self.repository.revisions(user_id, id)?)
     }
 
-    fn get_note_doc(
+    pub fn get_revision(
         &self,
         user_id: u64,
         id: DocumentId,
-    ) -> tantivy::Result<(DocAddress, Document)> {
-        let schema = self.index.schema();
-        let id_field = schema.get_field("id").unwrap();
-        let user_id_field = schema.get_field("user_id").unwrap();
+        revision: u64,
+    ) -> tantivy::Result<Revision> {
+        Ok(self.repository.revision(user_id, id, revision)?)
+    }
 
-        let id_query: Box<dyn Query> =
-            Box::new(RangeQuery::new_u64(id_field, id as u64..(id + 1) as u64));
-        let user_query: Box<dyn Query> = Box::new(RangeQuery::new_u64(
-            user_id_field,
-            user_id as u64..(user_id + 1) as u64,
-        ));
-        let compound_query =
-            BooleanQuery::from(vec![(Occur::Must, id_query), (Occur::Must, user_query)]);
+    pub fn tag_counts(&self, user_id: u64) -> tantivy::Result<Vec<TagCount>> {
+        let tags_field = self.index.schema().get_field("tags").unwrap();
+
+        let mut collector = FacetCollector::for_field(tags_field);
+        collector.add_facet(Facet::root());
 
         let searcher = self.reader.searcher();
-        let fruit = match searcher.search(&compound_query, &TopDocs::with_limit(1)) {
-            Ok(f) => f,
-            _ => return Err(Error::InvalidArgument(format!("{}, {}", user_id, id))),
-        };
+        let counts = searcher.search(&*self.user_query(user_id), &collector)?;
+
+        Ok(counts
+            .get(Facet::root())
+            .filter_map(|(facet, count)| {
+                facet.to_path().last().map(|tag| TagCount {
+                    tag: String::from(*tag),
+                    count,
+                })
+            })
+            .collect())
+    }
+
+    pub fn count_notes(&self, user_id: u64) -> tantivy::Result<usize> {
+        Ok(self.repository.count(user_id)?)
+    }
+
+    /// Replaces `from` with `to` on every one of the user's notes, merging the two if some notes
+    /// already have both. Returns the number of notes changed.
+    pub fn rename_tag(&self, user_id: u64, from: &str, to: &str) -> tantivy::Result<usize> {
+        let mut writer = self.writer.lock()?;
+        let changed = self.repository.rewrite(Some(user_id), &|stored| {
+            let note = &mut stored.note;
+            if stored.deleted_at.is_some() || !note.tags.iter().any(|tag| tag == from) {
+                return false;
+            }
+            for tag in note.tags.iter_mut() {
+                if tag == from {
+                    *tag = String::from(to);
+                }
+            }
+            note.tags.sort();
+            note.tags.dedup();
+            true
+        })?;
+        self.reindex_changed(&mut writer, changed)
+    }
+
+    /// Moves a note to the trash. It keeps its id and revisions until it is purged.
+    pub fn delete_note(&self, user_id: u64, id: DocumentId) -> tantivy::Result<(Note, Opstamp)> {
+        let mut writer = self.writer.lock()?;
+        let note = self.repository.trash(user_id, id, Utc::now())?;
+        let opstamp = self.reindex(&mut writer, user_id, id, None);
+        Ok((note, opstamp))
+    }
+
+    /// Lists the notes in the user's trash, most recently deleted first.
+    pub fn list_trash(&self, user_id: u64) -> tantivy::Result<Vec<TrashedNote>> {
+        Ok(self.repository.list_trash(user_id)?)
+    }
+
+    pub fn restore_note(&self, user_id: u64, id: DocumentId) -> tantivy::Result<(Note, Opstamp)> {
+        let mut writer = self.writer.lock()?;
+        let note = self.repository.restore(user_id, id)?;
+        let opstamp = self.reindex(&mut writer, user_id, id, Some(note.clone()));
+        Ok((note, opstamp))
+    }
+
+    /// Deletes every note in the user's trash for good. Returns the number of notes deleted.
+    pub fn empty_trash(&self, user_id: u64) -> tantivy::Result<usize> {
+        Ok(self.repository.empty_trash(user_id)?)
+    }
 
-        let addr = match fruit.as_slice() {
-            [(_, addr)] => addr,
-            _ => return Err(Error::InvalidArgument(format!("{}, {}", user_id, id))),
+    /// Deletes every user's notes that were moved to the trash before `cutoff`.
+    pub fn purge_trash(&self, cutoff: DateTime<Utc>) -> tantivy::Result<usize> {
+        Ok(self.repository.purge_trash(cutoff)?)
+    }
+
+    /// Starts a thread that purges notes from the trash once they have been there for longer
+    /// than `retention`, checking every `interval`.
+    pub fn purge_trash_periodically(&self, retention: chrono::Duration, interval: Duration) {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            match store.purge_trash(Utc::now() - retention) {
+                Ok(0) => {}
+                Ok(count) => println!("Purged {} notes from the trash", count),
+                Err(e) => println!("Could not purge the trash: {:?}", e),
+            }
+            thread::sleep(interval);
+        });
+    }
+
+    /// Deletes everything belonging to a user: their notes, revisions and trash.
+    pub fn delete_user_notes(&self, user_id: u64) -> tantivy::Result<()> {
+        let user_id_field = self.index.schema().get_field("user_id").unwrap();
+        let mut writer = self.writer.lock()?;
+        self.repository.delete_user(user_id)?;
+        writer
+            .index
+            .delete_term(Term::from_field_u64(user_id_field, user_id));
+        self.commit(&mut writer)
+    }
+
+    /// Throws away everything in the index and indexes every note in the repository again.
+    /// Returns the number of notes indexed.
+    pub fn rebuild_index(&self) -> tantivy::Result<usize> {
+        let mut writer = self.writer.lock()?;
+        writer.index.delete_all_documents()?;
+        let mut count = 0;
+        {
+            let index_writer = &writer.index;
+            self.repository.for_each_note(&mut |user_id, note| {
+                index_writer.add_document(self.note_document(user_id, note));
+                count += 1;
+            })?;
         }
-        .clone();
+        self.commit(&mut writer)?;
+        Ok(count)
+    }
 
-        Ok((addr, searcher.doc(addr).expect("WIE???")))
+    /// Commits pending changes and waits for the reader to see them, so that a request can
+    /// search for what it just wrote.
+    fn commit(&self, writer: &mut Writer) -> tantivy::Result<()> {
+        // Every change to the repository that the index follows is made while holding the
+        // writer, so the commit has all of the changes up to the repository's current seq.
+        let mut prepared = writer.index.prepare_commit()?;
+        prepared.set_payload(&self.payload()?);
+        let opstamp = prepared.commit()?;
+        self.reader.reload()?;
+        writer.pending_ops = 0;
+        self.searchable.store(opstamp, Ordering::SeqCst);
+        Ok(())
     }
 
-    fn load_note(&self, doc: Document) -> Note {
+    /// What a commit records: the version of the index's layout, and how far through the
+    /// repository's changes the index is. An index with anything else is rebuilt.
+    fn payload(&self) -> tantivy::Result<String> {
+        Ok(format!(
+            "{}:{}",
+            constants::INDEX_VERSION,
+            self.repository.seq()?
+        ))
+    }
+
+    /// Queues the index's copy of a note to be replaced with `note`, or removed if that's
+    /// `None`, waking the committer if that makes enough for a batch. Returns the opstamp of the
+    /// last operation.
+    fn reindex(
+        &self,
+        writer: &mut Writer,
+        user_id: u64,
+        id: DocumentId,
+        note: Option<Note>,
+    ) -> Opstamp {
+        let id_field = self.index.schema().get_field("id").unwrap();
+        let mut opstamp = writer
+            .index
+            .delete_term(Term::from_field_u64(id_field, id as u64));
+        if let Some(note) = note {
+            opstamp = writer.index.add_document(self.note_document(user_id, note));
+        }
+        writer.pending_ops += 1;
+        if writer.pending_ops >= self.commit_policy.max_pending {
+            self.commit_ready.notify_one();
+        }
+        opstamp
+    }
+
+    /// Updates the index with notes changed all at once, and commits them.
+    fn reindex_changed(
+        &self,
+        writer: &mut Writer,
+        changed: Vec<StoredNote>,
+    ) -> tantivy::Result<usize> {
+        let count = changed.len();
+        if count == 0 {
+            return Ok(0);
+        }
+        for stored in changed {
+            if stored.deleted_at.is_none() {
+                self.reindex(writer, stored.user_id, stored.note.id, Some(stored.note));
+            }
+        }
+        self.commit(writer)?;
+        Ok(count)
+    }
+
+    /// Starts the thread that commits queued writes, once there are enough of them or once
+    /// `max_delay` has passed.
+    fn commit_in_background(&self) {
+        let store = self.clone();
+        thread::spawn(move || {
+            let mut writer: MutexGuard<Writer> = match store.writer.lock() {
+                Ok(writer) => writer,
+                Err(_) => return,
+            };
+            loop {
+                writer = match store
+                    .commit_ready
+                    .wait_timeout(writer, store.commit_policy.max_delay)
+                {
+                    Ok((writer, _)) => writer,
+                    Err(_) => return,
+                };
+                if writer.pending_ops > 0 {
+                    if let Err(e) = store.commit(&mut writer) {
+                        println!("Could not commit to the index: {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    fn user_query(&self, user_id: u64) -> Box<dyn Query> {
+        let user_id_field = self.index.schema().get_field("user_id").unwrap();
+        Box::new(RangeQuery::new_u64(user_id_field, user_id..user_id + 1))
+    }
+
+    fn note_document(&self, user_id: u64, note: Note) -> Document {
         let schema = self.index.schema();
         let id_field = schema.get_field("id").unwrap();
         let title_field = schema.get_field("title").unwrap();
         let body_field = schema.get_field("body").unwrap();
-        return Note {
-            id: doc.get_first(id_field).unwrap().u64_value() as DocumentId,
-            title: String::from(doc.get_first(title_field).unwrap().text().unwrap()),
-            body: String::from(doc.get_first(body_field).unwrap().text().unwrap()),
-        };
+        let user_id_field = schema.get_field("user_id").unwrap();
+        let title_sort_field = schema.get_field("title_sort").unwrap();
+        let created_field = schema.get_field("created_at").unwrap();
+        let updated_field = schema.get_field("updated_at").unwrap();
+        let tags_field = schema.get_field("tags").unwrap();
+        let version_field = schema.get_field("version").unwrap();
+
+        let mut doc = doc!(
+            id_field => note.id as u64,
+            title_sort_field => title_sort_key(&note.title),
+            title_field => note.title,
+            body_field => note.body,
+            user_id_field => user_id,
+            created_field => note.created_at,
+            updated_field => note.updated_at,
+            version_field => note.version,
+        );
+        // Every note gets the root facet, even without tags. A segment where no document has a
+        // facet can't be read by the FacetCollector.
+        doc.add_facet(tags_field, Facet::root());
+        for tag in note.tags {
+            doc.add_facet(tags_field, Facet::from_path(vec![tag]));
+        }
+        doc
     }
 
-    pub fn next_id(&self) -> tantivy::Result<DocumentId> {
-        let schema = self.index.schema();
-        let id_field = schema.get_field("id").unwrap();
-        let searcher = self.reader.searcher();
-        let fruit = searcher.search(
-            &AllQuery,
-            &TopDocs::with_limit(1).order_by_u64_field(id_field),
-        )?;
-        match fruit.as_slice() {
-            [(_, addr)] => {
-                let doc = searcher.doc(*addr).unwrap();
-                let note = self.load_note(doc);
-                Ok(note.id + 1)
+    fn load_note(&self, doc: Document) -> Note {
+        load_note(&self.index.schema(), doc)
+    }
+}
+
+/// Whether the index was made with some other schema than `schema`. Before notes were kept in a
+/// `NoteRepository` the index held every note itself, along with their revisions and the trash
+/// in some versions, so any such index has to have its notes moved out before it's replaced.
+fn is_legacy_index(index_dir: &str, schema: &Schema) -> tantivy::Result<bool> {
+    let directory = MmapDirectory::open(index_dir)?;
+    if !Index::exists(&directory) {
+        return Ok(false);
+    }
+    let index = Index::open(directory)?;
+    Ok(index.schema() != *schema)
+}
+
+/// Moves the notes, revisions and trash out of a legacy index and into the repository, along
+/// with the changes in its write-ahead log that never reached a commit. The old index is then
+/// renamed, so this only happens once. Older indexes lack many of the fields, and whatever is
+/// missing is filled in by `load_note`.
+fn migrate_legacy_index(index_dir: &str, repository: &dyn NoteRepository) -> tantivy::Result<()> {
+    let index_dir = index_dir.trim_end_matches('/');
+    if repository.seq()? > 0 {
+        // the database was written to after this index stopped being used
+        println!(
+            "Setting aside the old index at {}, since the note database already has notes",
+            index_dir
+        );
+    } else {
+        let index = Index::open_in_dir(index_dir)?;
+        let schema = index.schema();
+        let user_id_field = schema.get_field("user_id").unwrap();
+        let note_id_field = schema.get_field("note_id");
+        let revision_field = schema.get_field("revision");
+        let revision_title_field = schema.get_field("revision_title");
+        let revision_body_field = schema.get_field("revision_body");
+        let updated_field = schema.get_field("updated_at");
+        let deleted_field = schema.get_field("deleted_at");
+        let text = |doc: &Document, field: Option<Field>| {
+            field
+                .and_then(|field| doc.get_first(field))
+                .and_then(Value::text)
+                .map(String::from)
+                .unwrap_or_default()
+        };
+
+        let mut notes = HashMap::new();
+        let mut revisions = Vec::new();
+        let searcher = index.reader()?.searcher();
+        let count = searcher.search(&AllQuery, &Count)?;
+        if count > 0 {
+            for (_, addr) in searcher.search(&AllQuery, &TopDocs::with_limit(count))? {
+                let doc = searcher.doc(addr)?;
+                let user_id = doc.get_first(user_id_field).unwrap().u64_value();
+                // revisions are the only documents with a revision number
+                if let Some(revision) = revision_field.and_then(|field| doc.get_first(field)) {
+                    let note_id = note_id_field.and_then(|field| doc.get_first(field));
+                    let saved_at = updated_field.and_then(|field| doc.get_first(field));
+                    if let (Some(note_id), Some(saved_at)) = (note_id, saved_at) {
+                        revisions.push(StoredRevision {
+                            note_id: note_id.u64_value() as DocumentId,
+                            revision: Revision {
+                                revision: revision.u64_value(),
+                                title: text(&doc, revision_title_field),
+                                body: text(&doc, revision_body_field),
+                                saved_at: *saved_at.date_value(),
+                            },
+                        });
+                    }
+                    continue;
+                }
+                let deleted_at = deleted_field
+                    .and_then(|field| doc.get_first(field))
+                    .map(|v| *v.date_value());
+                let note = load_note(&schema, doc);
+                notes.insert(
+                    note.id,
+                    StoredNote {
+                        user_id,
+                        note,
+                        deleted_at,
+                    },
+                );
+            }
+        }
+
+        let committed_seq = index
+            .load_metas()?
+            .payload
+            .and_then(|payload| payload.parse().ok())
+            .unwrap_or(0);
+        let log_path = Path::new(index_dir).join(constants::NOTE_LOG_FILE);
+        for entry in wal::read_entries(&log_path)? {
+            if entry.seq <= committed_seq {
+                continue;
             }
-            _ => Ok(0),
+            let (user_id, note, deleted_at) = match entry.change {
+                NoteChange::Add { user_id, note } | NoteChange::Restore { user_id, note } => {
+                    (user_id, note, None)
+                }
+                NoteChange::Update {
+                    user_id,
+                    note,
+                    previous,
+                    revision,
+                } => {
+                    revisions.push(StoredRevision {
+                        note_id: note.id,
+                        revision: Revision {
+                            revision,
+                            title: previous.title,
+                            body: previous.body,
+                            saved_at: previous.updated_at,
+                        },
+                    });
+                    (user_id, note, None)
+                }
+                NoteChange::Trash {
+                    user_id,
+                    note,
+                    deleted_at,
+                } => (user_id, note, Some(deleted_at)),
+            };
+            notes.insert(
+                note.id,
+                StoredNote {
+                    user_id,
+                    note,
+                    deleted_at,
+                },
+            );
         }
+
+        let notes: Vec<StoredNote> = notes.into_iter().map(|(_, stored)| stored).collect();
+        let count = repository.import(&notes, &revisions)?;
+        println!(
+            "Moved {} notes and {} revisions from the index at {} into the note database",
+            count,
+            revisions.len(),
+            index_dir
+        );
+    }
+
+    fs::rename(
+        index_dir,
+        format!("{}{}", index_dir, constants::MIGRATED_SUFFIX),
+    )?;
+    Ok(())
+}
+
+/// Reads a note from its document. Only the id, title and body are in every version of the
+/// schema; a legacy index may be missing the rest, so notes from one get no tags, version 1 and
+/// the time they're read as their update time, which also stands in for a missing creation time.
+fn load_note(schema: &Schema, doc: Document) -> Note {
+    let id_field = schema.get_field("id").unwrap();
+    let title_field = schema.get_field("title").unwrap();
+    let body_field = schema.get_field("body").unwrap();
+    let date = |name: &str| {
+        schema
+            .get_field(name)
+            .and_then(|field| doc.get_first(field))
+            .map(|v| *v.date_value())
+    };
+    let updated_at = date("updated_at").unwrap_or_else(Utc::now);
+    let tags = match schema.get_field("tags") {
+        Some(tags_field) => doc
+            .get_all(tags_field)
+            .into_iter()
+            .filter_map(|v| match v {
+                Value::Facet(facet) if !facet.is_root() => {
+                    facet.to_path().last().map(|t| String::from(*t))
+                }
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    Note {
+        id: doc.get_first(id_field).unwrap().u64_value() as DocumentId,
+        title: String::from(doc.get_first(title_field).unwrap().text().unwrap()),
+        body: String::from(doc.get_first(body_field).unwrap().text().unwrap()),
+        tags,
+        created_at: date("created_at").unwrap_or(updated_at),
+        updated_at,
+        version: schema
+            .get_field("version")
+            .and_then(|field| doc.get_first(field))
+            .map_or(1, Value::u64_value),
     }
 }
 
+/// Packs the first eight bytes of the lowercased title into an integer, which sorts the same
+/// way as the titles do up to that length. It's only a coarse key: titles that start with the
+/// same eight bytes, like "Meeting notes" and "Meeting agenda", share it and are ranked by id
+/// instead, so their whole titles have to be compared once the notes are loaded.
+fn title_sort_key(title: &str) -> u64 {
+    let mut bytes = [0u8; 8];
+    for (dest, src) in bytes.iter_mut().zip(title.trim().to_lowercase().bytes()) {
+        *dest = src;
+    }
+    u64::from_be_bytes(bytes)
+}
+
+/// Orders notes that share a title sort key by their whole lowercased title. The notes must
+/// already be in order of their keys; the sort is stable, so notes with the same title keep
+/// their order by id.
+fn order_by_full_title<T>(items: &mut [T], note: impl Fn(&T) -> &Note, descending: bool) {
+    let title_order = |a: &Note, b: &Note| {
+        let (a, b) = (a.title.trim().to_lowercase(), b.title.trim().to_lowercase());
+        (title_sort_key(&a), a).cmp(&(title_sort_key(&b), b))
+    };
+    items.sort_by(|a, b| {
+        let order = title_order(note(a), note(b));
+        if descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+}
+
 fn build_search_query(index: &Index, fields: Vec<Field>, text: &str) -> Box<dyn Query> {
     let mut term_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
-    for f in fields.clone().into_iter() {
+    for f in fields.into_iter() {
         let mut tokens = Vec::new();
         let tokenizer = index.tokenizer_for_field(f).unwrap();
         let mut stream = tokenizer.token_stream(text);
@@ -370,3 +1315,422 @@ fn build_multiterm_query(field: Field, tokens: Vec<String>) -> Box<dyn Query> {
     }
     Box::new(BooleanQuery::from(term_queries))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::SqliteNoteRepository;
+
+    /// A fresh directory for a test's note database and indexes.
+    fn test_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("soash-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        String::from(dir.to_str().unwrap())
+    }
+
+    /// Opens the index `index_name` in `dir`, along with the note database there. Nothing is
+    /// committed in the background, so writes stay uncommitted until they're waited for.
+    fn open_store(dir: &str, index_name: &str) -> NoteStore {
+        let repository = SqliteNoteRepository::open(&format!("{}/notes.db", dir)).unwrap();
+        let commit_policy = CommitPolicy {
+            max_pending: std::usize::MAX,
+            max_delay: Duration::from_secs(3600),
+        };
+        NoteStore::new(
+            format!("{}/{}", dir, index_name),
+            Arc::new(repository),
+            commit_policy,
+        )
+        .unwrap()
+    }
+
+    /// Copies an index as it is on disk, which is what's left of it if the server dies then.
+    /// Files the index deletes while they're being copied are no longer part of it, and neither
+    /// are the directories it writes files in before moving them into place.
+    fn copy_index(dir: &str, from: &str, to: &str) {
+        let to = format!("{}/{}", dir, to);
+        fs::create_dir_all(&to).unwrap();
+        for entry in fs::read_dir(format!("{}/{}", dir, from)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                continue;
+            }
+            if let Err(e) = fs::copy(&path, Path::new(&to).join(path.file_name().unwrap())) {
+                assert!(!path.exists(), "{:?}", e);
+            }
+        }
+    }
+
+    /// The stores' background threads keep running until the tests finish, so their files
+    /// might not all be gone.
+    fn remove_dir(dir: &str) {
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    fn search_titles(store: &NoteStore, user_id: u64, query: &str) -> Vec<String> {
+        let options = SearchOptions {
+            sort: None,
+            descending: false,
+            count: 10,
+            created: DateRange::default(),
+        };
+        let mut titles: Vec<String> = store
+            .search_notes(user_id, query, &options)
+            .unwrap()
+            .into_iter()
+            .map(|hit| hit.note.title)
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    fn note(title: &str, body: &str) -> Note {
+        Note::new(String::from(title), String::from(body), &[])
+    }
+
+    /// Makes an index with the schema it had before any notes were kept anywhere else, holding
+    /// `(id, user_id, title, body)` notes and committed with `payload`.
+    fn create_original_index(index_dir: &str, notes: &[(u64, u64, &str, &str)], payload: &str) {
+        let mut builder = Schema::builder();
+        let text_options = TextOptions::default()
+            .set_indexing_options(TextFieldIndexing::default().set_tokenizer("en_html"))
+            .set_stored();
+        let id_field = builder.add_u64_field("id", STORED | INDEXED | FAST);
+        let user_id_field = builder.add_u64_field("user_id", STORED | INDEXED | FAST);
+        let title_field = builder.add_text_field("title", text_options.clone());
+        let body_field = builder.add_text_field("body", text_options);
+
+        fs::create_dir_all(index_dir).unwrap();
+        let index = Index::create_in_dir(index_dir, builder.build()).unwrap();
+        index.tokenizers().register("en_html", HtmlTokenizer);
+        let mut writer = index.writer(constants::INDEXER_HEAP_SIZE).unwrap();
+        for &(id, user_id, title, body) in notes {
+            writer.add_document(doc!(
+                id_field => id,
+                user_id_field => user_id,
+                title_field => title,
+                body_field => body,
+            ));
+        }
+        let mut prepared = writer.prepare_commit().unwrap();
+        prepared.set_payload(payload);
+        prepared.commit().unwrap();
+    }
+
+    #[test]
+    fn migrates_an_index_with_the_original_schema() {
+        let dir = test_dir();
+        let index_dir = format!("{}/index", dir);
+        create_original_index(
+            &index_dir,
+            &[
+                (0, 1, "Pasta", "<p>boil water</p>"),
+                (1, 2, "Bread", "<p>knead the dough</p>"),
+            ],
+            "",
+        );
+
+        let store = open_store(&dir, "index");
+        let pasta = store.get_note(1, 0).unwrap();
+        assert_eq!(pasta.title, "Pasta");
+        assert_eq!(pasta.body, "<p>boil water</p>");
+        assert!(pasta.tags.is_empty());
+        assert_eq!(pasta.version, 1);
+        assert_eq!(pasta.created_at, pasta.updated_at);
+        assert_eq!(store.get_note(2, 1).unwrap().title, "Bread");
+        assert_eq!(search_titles(&store, 1, "water"), vec!["Pasta"]);
+        assert_eq!(search_titles(&store, 2, "dough"), vec!["Bread"]);
+        assert!(Path::new(&format!("{}{}", index_dir, constants::MIGRATED_SUFFIX)).exists());
+
+        // new notes carry on from the old ids
+        let (id, _) = store.add_note(1, note("Soup", "")).unwrap();
+        assert_eq!(id, 2);
+
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn rebuilds_an_index_that_missed_writes() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let (apple, opstamp) = store.add_note(1, note("Apple", "fruit")).unwrap();
+        store.wait_for(opstamp).unwrap();
+        // in the note database, but never committed to the index
+        store.add_note(1, note("Banana", "fruit")).unwrap();
+        store.delete_note(1, apple).unwrap();
+        assert_eq!(search_titles(&store, 1, "fruit"), vec!["Apple"]);
+
+        copy_index(&dir, "index", "crashed");
+        let reopened = open_store(&dir, "crashed");
+        assert_eq!(search_titles(&reopened, 1, "fruit"), vec!["Banana"]);
+
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn rebuilds_an_index_that_is_behind_the_note_database() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let opstamp = store.add_note(1, note("Apple", "fruit")).unwrap().1;
+        store.wait_for(opstamp).unwrap();
+        copy_index(&dir, "index", "behind");
+        let repository = SqliteNoteRepository::open(&format!("{}/notes.db", dir)).unwrap();
+        repository.add(1, &note("Banana", "fruit")).unwrap();
+        let seq = repository.seq().unwrap();
+
+        let payload = || {
+            let index = Index::open_in_dir(format!("{}/behind", dir)).unwrap();
+            index.load_metas().unwrap().payload.unwrap()
+        };
+        let committed_at = |seq| format!("{}:{}", constants::INDEX_VERSION, seq);
+        assert_eq!(payload(), committed_at(seq - 1));
+        let reopened = open_store(&dir, "behind");
+        assert_eq!(
+            search_titles(&reopened, 1, "fruit"),
+            vec!["Apple", "Banana"]
+        );
+        assert_eq!(payload(), committed_at(seq));
+
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn keeps_an_index_that_is_up_to_date() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let (_, opstamp) = store.add_note(1, note("Apple", "fruit")).unwrap();
+        store.wait_for(opstamp).unwrap();
+        let committed = store.index.load_metas().unwrap().opstamp;
+
+        copy_index(&dir, "index", "copy");
+        let reopened = open_store(&dir, "copy");
+        assert_eq!(reopened.index.load_metas().unwrap().opstamp, committed);
+        assert_eq!(search_titles(&reopened, 1, "fruit"), vec!["Apple"]);
+
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn writes_are_searchable_once_waited_for() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let (id, added) = store.add_note(1, note("Apple", "fruit")).unwrap();
+        assert!(search_titles(&store, 1, "fruit").is_empty());
+        store.wait_for(added).unwrap();
+        assert_eq!(search_titles(&store, 1, "fruit"), vec!["Apple"]);
+
+        let updated = store
+            .update_note(1, id, note("Apple", "tree"), None)
+            .unwrap();
+        // already committed, so waiting for it doesn't commit the update
+        store.wait_for(added).unwrap();
+        assert!(search_titles(&store, 1, "tree").is_empty());
+        store.wait_for(updated).unwrap();
+        assert_eq!(search_titles(&store, 1, "tree"), vec!["Apple"]);
+        assert!(search_titles(&store, 1, "fruit").is_empty());
+
+        remove_dir(&dir);
+    }
+
+    /// A line of the old write-ahead log.
+    fn log_line(seq: u64, op: &str, id: u64, title: &str, extra: &str) -> String {
+        format!(
+            r#"{{"seq":{},"change":{{"op":"{}","user_id":1,"note":{{"id":{},"title":"{}","body":"","created_at":"2020-01-01T00:00:00Z","updated_at":"2020-01-02T00:00:00Z","version":1}}{}}}}}"#,
+            seq, op, id, title, extra
+        ) + "\n"
+    }
+
+    #[test]
+    fn migrates_the_changes_logged_after_the_last_commit() {
+        let dir = test_dir();
+        let index_dir = format!("{}/index", dir);
+        // the index committed up to entry 2, which is all of the changes to note 0
+        create_original_index(&index_dir, &[(0, 1, "Apple pie", "")], "2");
+        let previous = r#","previous":{"id":0,"title":"Apple","body":"","created_at":"2020-01-01T00:00:00Z","updated_at":"2020-01-01T00:00:00Z"},"revision":1"#;
+        let trashed = r#","deleted_at":"2020-01-03T00:00:00Z""#;
+        let log = [
+            // committed, but the server stopped before the log was cleared
+            log_line(1, "add", 0, "Apple", ""),
+            log_line(2, "update", 0, "Apple pie", previous),
+            // appended, but the server stopped before they were committed
+            log_line(3, "add", 1, "Banana", ""),
+            log_line(4, "trash", 1, "Banana", trashed),
+            log_line(5, "restore", 1, "Banana", ""),
+            log_line(6, "add", 2, "Cherry", ""),
+        ]
+        .concat();
+        // the server stopped partway through writing the last entry
+        let torn = &log[..log.len() - 20];
+        fs::write(Path::new(&index_dir).join(constants::NOTE_LOG_FILE), torn).unwrap();
+
+        let store = open_store(&dir, "index");
+        assert_eq!(store.get_note(1, 0).unwrap().title, "Apple pie");
+        // entry 2 was already in the index, so its revision isn't made again
+        assert!(store.list_revisions(1, 0).unwrap().is_empty());
+        assert_eq!(store.get_note(1, 1).unwrap().title, "Banana");
+        assert!(store.list_trash(1).unwrap().is_empty());
+        assert!(store.get_note(1, 2).is_err());
+        assert_eq!(store.count_notes(1).unwrap(), 2);
+
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn titles_that_share_a_sort_key_are_ordered_in_full() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let mut opstamp = 0;
+        let added = [
+            "Shopping: pears",
+            "Zebra",
+            "shopping: apples",
+            "Apple",
+            "Shopping: figs",
+        ];
+        for title in added.iter() {
+            opstamp = store.add_note(1, note(title, "")).unwrap().1;
+        }
+        store.wait_for(opstamp).unwrap();
+        let titles = |notes: Vec<Note>| -> Vec<String> {
+            notes.into_iter().map(|note| note.title).collect()
+        };
+
+        let mut options = ListOptions {
+            sort: SortField::Title,
+            descending: false,
+            limit: 10,
+            after: None,
+            created: DateRange::default(),
+        };
+        let mut expected = vec![
+            "Apple",
+            "shopping: apples",
+            "Shopping: figs",
+            "Shopping: pears",
+            "Zebra",
+        ];
+        let page = store.list_notes(1, &options).unwrap();
+        assert_eq!(titles(page.notes), expected);
+        options.descending = true;
+        expected.reverse();
+        let page = store.list_notes(1, &options).unwrap();
+        assert_eq!(titles(page.notes), expected);
+
+        let options = SearchOptions {
+            sort: Some(SortField::Title),
+            descending: false,
+            count: 10,
+            created: DateRange::default(),
+        };
+        let hits = store.search_notes(1, "shopping", &options).unwrap();
+        let notes = hits.into_iter().map(|hit| hit.note).collect();
+        assert_eq!(
+            titles(notes),
+            vec!["shopping: apples", "Shopping: figs", "Shopping: pears"]
+        );
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn pages_of_titles_that_share_a_sort_key_are_in_order() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let mut opstamp = 0;
+        // added backwards, so that their ids are in the opposite order to their titles
+        let mut expected = vec![String::from("Apple")];
+        expected.extend((1..=9).map(|day| format!("Meeting {}", day)));
+        expected.push(String::from("Zebra"));
+        for title in expected.iter().rev() {
+            opstamp = store.add_note(1, note(title, "")).unwrap().1;
+        }
+        store.wait_for(opstamp).unwrap();
+
+        for &descending in [false, true].iter() {
+            let mut options = ListOptions {
+                sort: SortField::Title,
+                descending,
+                limit: 2,
+                after: None,
+                created: DateRange::default(),
+            };
+            let mut titles = Vec::new();
+            loop {
+                let page = store.list_notes(1, &options).unwrap();
+                assert!(page.notes.len() <= 2);
+                titles.extend(page.notes.into_iter().map(|note| note.title));
+                match page.next_cursor {
+                    Some(cursor) => options.after = NoteCursor::decode(&cursor),
+                    None => break,
+                }
+            }
+            if descending {
+                expected.reverse();
+            }
+            assert_eq!(titles, expected);
+        }
+        remove_dir(&dir);
+    }
+
+    fn snippets(store: &NoteStore, query: &str) -> Vec<String> {
+        let options = SearchOptions {
+            sort: None,
+            descending: false,
+            count: 10,
+            created: DateRange::default(),
+        };
+        let mut hits = store.search_notes(1, query, &options).unwrap();
+        assert_eq!(hits.len(), 1, "{}", query);
+        hits.pop().unwrap().snippets
+    }
+
+    #[test]
+    fn snippets_mark_the_words_searched_for_in_the_html() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let body = "<p>Boil the <b>pasta</b>, then add the sauce.</p>";
+        store.add_note(1, note("a", body)).unwrap();
+        let body = "<p>Fish &amp; chips&nbsp;&amp; peas</p><p>caf&eacute; au lait</p>";
+        let opstamp = store.add_note(1, note("b", body)).unwrap().1;
+        store.wait_for(opstamp).unwrap();
+
+        assert_eq!(
+            snippets(&store, "pasta"),
+            vec!["Boil the <mark>pasta</mark>, then add the sauce"]
+        );
+        assert_eq!(
+            snippets(&store, "chips"),
+            vec!["Fish &amp; <mark>chips</mark>&nbsp;&amp; peas caf&eacute; au lait"]
+        );
+        assert_eq!(
+            snippets(&store, "lait"),
+            vec!["Fish &amp; chips&nbsp;&amp; peas caf&eacute; au <mark>lait</mark>"]
+        );
+        // the note doesn't have both, so it matches, but what's excluded isn't marked
+        assert_eq!(
+            snippets(&store, "pasta -(+sauce +cream)"),
+            vec!["Boil the <mark>pasta</mark>, then add the sauce"]
+        );
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn snippets_are_cut_to_the_words_around_a_match() {
+        let dir = test_dir();
+        let store = open_store(&dir, "index");
+        let words = |range: std::ops::RangeInclusive<u32>| {
+            let words: Vec<String> = range.map(|i| format!("w{}", i)).collect();
+            words.join(" ")
+        };
+        let body = format!("{} pasta {}", words(1..=20), words(21..=40));
+        let opstamp = store.add_note(1, note("a", &body)).unwrap().1;
+        store.wait_for(opstamp).unwrap();
+
+        let expected = format!(
+            "… {} <mark>pasta</mark> {} …",
+            words(13..=20),
+            words(21..=35)
+        );
+        assert_eq!(snippets(&store, "pasta"), vec![expected]);
+        remove_dir(&dir);
+    }
+}
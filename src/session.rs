@@ -0,0 +1,416 @@
+use chrono::{DateTime, Utc};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use rocket::{
+    request::{FromRequest, Request},
+    Outcome,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use uuid::{adapter::Hyphenated, Uuid};
+
+use crate::{
+    auth::{self, hash_token, AuthenticatedUser, AuthenticationError},
+    cache::{CacheOptions, CacheStats, TtlCache},
+    constants,
+};
+
+/// What's kept on disk for a session token. The key it's stored under is the hash of the token.
+/// A session keeps its id when its token is rotated; the replaced token's record is kept, marked
+/// as rotated, until it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSession {
+    id: String,
+    user_id: u64,
+    name: String,
+    /// When the user logged in.
+    created: DateTime<Utc>,
+    /// When this particular token was handed out; the token expires relative to this.
+    issued: DateTime<Utc>,
+    last_used: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    #[serde(default)]
+    rotated: bool,
+}
+
+/// A session as shown to its user.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+/// The client a request came from, as recorded against its session.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, (rocket::http::Status, ()), ()> {
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            ip: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+/// Sessions by hashed token, cached in memory and written through to disk so they outlive a
+/// restart. The raw token only ever exists in the user's cookie. Sessions that have been evicted
+/// from the cache are read back from disk when they're next used.
+pub struct SessionStore {
+    cache: TtlCache<AuthenticatedUser>,
+    db: RwLock<PickleDb>,
+}
+
+impl SessionStore {
+    pub fn new(db_path: &str, expiry: Duration, cache_options: CacheOptions) -> Self {
+        let db = PickleDb::load(
+            db_path,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        );
+
+        let mut db = match db {
+            Ok(db) => db,
+            Err(_) => PickleDb::new(
+                db_path,
+                PickleDbDumpPolicy::AutoDump,
+                SerializationMethod::Json,
+            ),
+        };
+
+        let cache = TtlCache::with_options(expiry, cache_options);
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        for item in db.iter() {
+            let key = String::from(item.get_key());
+            let session: StoredSession = match item.get_value() {
+                Some(session) => session,
+                None => {
+                    expired.push(key);
+                    continue;
+                }
+            };
+            match session.issued_instant(now, expiry) {
+                Some(created) => cache.insert_with_time(&key, session.user(), created),
+                None => expired.push(key),
+            }
+        }
+        for key in expired {
+            let _ = db.rem(&key);
+        }
+
+        SessionStore {
+            cache,
+            db: RwLock::new(db),
+        }
+    }
+
+    pub fn get_expiry(&self) -> Duration {
+        self.cache.get_expiry()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Starts a new session for a user and returns its token.
+    pub fn create(
+        &self,
+        user_id: u64,
+        name: &str,
+        client: &ClientInfo,
+    ) -> Result<String, AuthenticationError> {
+        let now = Utc::now();
+        self.issue(StoredSession {
+            id: format!("{}", Hyphenated::from(Uuid::new_v4())),
+            user_id,
+            name: String::from(name),
+            created: now,
+            issued: now,
+            last_used: now,
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+            rotated: false,
+        })
+    }
+
+    /// Looks up the session for a token, recording that it was used.
+    pub fn get(&self, token: &str, client: &ClientInfo) -> Option<AuthenticatedUser> {
+        let key = hash_token(token);
+        let (mut user, _) = self.lookup(&key)?;
+        user.token = String::from(token);
+
+        // Saving every request would rewrite the whole store each time, so the last use is only
+        // kept to within a minute.
+        let now = Utc::now();
+        let stale = |session: &StoredSession| {
+            (now - session.last_used).num_seconds() >= constants::SESSION_LAST_USE_RESOLUTION
+                || session.ip != client.ip
+        };
+        let needs_update = match self.db.read() {
+            Ok(db) => db.get::<StoredSession>(&key).map_or(false, |s| stale(&s)),
+            Err(_) => false,
+        };
+        if needs_update {
+            if let Ok(mut db) = self.db.write() {
+                if let Some(mut session) = db.get::<StoredSession>(&key) {
+                    session.last_used = now;
+                    session.ip = client.ip.clone();
+                    let _ = db.set(&key, &session);
+                }
+            }
+        }
+
+        Some(user)
+    }
+
+    /// Issues a replacement token once a session is past half its lifetime, so that active users
+    /// stay logged in. The old token keeps working until it expires.
+    pub fn refresh(&self, token: &str) -> Option<String> {
+        let key = hash_token(token);
+        let (_, created) = self.lookup(&key)?;
+        if created.elapsed() <= self.get_expiry() / 2 {
+            return None;
+        }
+
+        let mut session: StoredSession = {
+            let mut db = self.db.write().ok()?;
+            let mut session: StoredSession = db.get(&key)?;
+            if session.rotated {
+                // a request that raced with the rotation; the client already has a new token
+                return None;
+            }
+            session.rotated = true;
+            db.set(&key, &session).ok()?;
+            session
+        };
+        session.issued = Utc::now();
+        session.rotated = false;
+        self.issue(session).ok()
+    }
+
+    /// Ends the session that `token` belongs to.
+    pub fn remove(&self, token: &str) -> Result<(), AuthenticationError> {
+        let session: Option<StoredSession> = self.db.read()?.get(&hash_token(token));
+        match session {
+            Some(session) => self.remove_where(|s| s.id == session.id).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Lists a user's sessions, most recently used first.
+    pub fn list(&self, user: &AuthenticatedUser) -> Result<Vec<SessionInfo>, AuthenticationError> {
+        let db = self.db.read()?;
+        let mut sessions: Vec<SessionInfo> = db
+            .iter()
+            .filter_map(|item| item.get_value::<StoredSession>())
+            .filter(|s| s.user_id == user.id && !s.rotated)
+            .map(|s| SessionInfo {
+                current: s.id == user.session_id,
+                id: s.id,
+                created: s.created,
+                last_used: s.last_used,
+                user_agent: s.user_agent,
+                ip: s.ip,
+            })
+            .collect();
+        sessions.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        Ok(sessions)
+    }
+
+    /// Ends one of a user's sessions. Returns whether there was such a session.
+    pub fn revoke(&self, user_id: u64, session_id: &str) -> Result<bool, AuthenticationError> {
+        let count = self.remove_where(|s| s.user_id == user_id && s.id == session_id)?;
+        Ok(count > 0)
+    }
+
+    /// Ends all of a user's sessions except the one making the request. Returns the number of
+    /// sessions ended.
+    pub fn revoke_others(&self, user: &AuthenticatedUser) -> Result<usize, AuthenticationError> {
+        let mut ended = Vec::new();
+        self.remove_where(|s| {
+            let other = s.user_id == user.id && s.id != user.session_id;
+            if other && !s.rotated {
+                ended.push(s.id.clone());
+            }
+            other
+        })?;
+        Ok(ended.len())
+    }
+
+    /// Ends all of a user's sessions, for when the user is deleted.
+    pub fn revoke_all(&self, user_id: u64) -> Result<usize, AuthenticationError> {
+        self.remove_where(|s| s.user_id == user_id)
+    }
+
+    /// Finds a session in the cache, or on disk if it has been evicted, along with when its token
+    /// was issued.
+    fn lookup(&self, key: &str) -> Option<(AuthenticatedUser, Instant)> {
+        if let Some(found) = self.cache.get_with_time(key) {
+            return Some(found);
+        }
+        let session: StoredSession = self.db.read().ok()?.get(key)?;
+        let created = session.issued_instant(Utc::now(), self.get_expiry())?;
+        let user = session.user();
+        self.cache.insert_with_time(key, user.clone(), created);
+        Some((user, created))
+    }
+
+    fn issue(&self, session: StoredSession) -> Result<String, AuthenticationError> {
+        let token = auth::generate_session_token();
+        let key = hash_token(&token);
+
+        self.db.write()?.set(&key, &session)?;
+        self.cache.insert(&key, session.user());
+        Ok(token)
+    }
+
+    /// Removes every stored token whose session matches, returning how many were removed.
+    fn remove_where<F>(&self, mut matches: F) -> Result<usize, AuthenticationError>
+    where
+        F: FnMut(&StoredSession) -> bool,
+    {
+        let mut db = self.db.write()?;
+        let keys: Vec<String> = db
+            .iter()
+            .filter(|item| {
+                item.get_value::<StoredSession>()
+                    .map_or(false, |s| matches(&s))
+            })
+            .map(|item| String::from(item.get_key()))
+            .collect();
+        for key in keys.iter() {
+            self.cache.remove(key);
+            db.rem(key)?;
+        }
+        Ok(keys.len())
+    }
+}
+
+impl StoredSession {
+    /// When the token was issued as an `Instant`, for the cache, or `None` if it has expired.
+    fn issued_instant(&self, now: DateTime<Utc>, expiry: Duration) -> Option<Instant> {
+        let age = (now - self.issued).to_std().unwrap_or_default();
+        if age >= expiry {
+            return None;
+        }
+        Some(Instant::now().checked_sub(age).unwrap_or_else(Instant::now))
+    }
+
+    fn user(&self) -> AuthenticatedUser {
+        AuthenticatedUser {
+            id: self.user_id,
+            name: self.name.clone(),
+            token: String::new(),
+            session_id: self.id.clone(),
+            scope: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    const EXPIRY: Duration = Duration::from_secs(3600);
+
+    /// A session database in a fresh directory, which goes when this does.
+    struct TestDb {
+        dir: PathBuf,
+        path: String,
+    }
+
+    impl TestDb {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("soash-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = String::from(dir.join("sessions.db").to_str().unwrap());
+            TestDb { dir, path }
+        }
+
+        fn open(&self) -> SessionStore {
+            SessionStore::new(&self.path, EXPIRY, CacheOptions::default())
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn sessions_outlive_a_restart_without_their_tokens_on_disk() {
+        let db = TestDb::new();
+        let client = ClientInfo::default();
+        let token = db.open().create(1, "alice", &client).unwrap();
+
+        let stored = fs::read_to_string(&db.path).unwrap();
+        assert!(!stored.contains(&token));
+        assert!(stored.contains(&hash_token(&token)));
+
+        let user = db.open().get(&token, &client).unwrap();
+        assert_eq!((user.id, user.name.as_str()), (1, "alice"));
+        assert!(db.open().get("made-up", &client).is_none());
+    }
+
+    #[test]
+    fn sessions_can_be_listed_and_ended() {
+        let db = TestDb::new();
+        let store = db.open();
+        let client = |agent: &str| ClientInfo {
+            user_agent: Some(String::from(agent)),
+            ip: Some(String::from("10.0.0.1")),
+        };
+        let phone = store.create(1, "alice", &client("phone")).unwrap();
+        let laptop = store.create(1, "alice", &client("laptop")).unwrap();
+        let tablet = store.create(1, "alice", &client("tablet")).unwrap();
+        let bob = store.create(2, "bob", &client("phone")).unwrap();
+
+        let alice = store.get(&phone, &client("phone")).unwrap();
+        let sessions = store.list(&alice).unwrap();
+        let mut agents: Vec<&str> = sessions
+            .iter()
+            .map(|s| s.user_agent.as_ref().unwrap().as_str())
+            .collect();
+        agents.sort();
+        assert_eq!(agents, vec!["laptop", "phone", "tablet"]);
+        let current: Vec<&SessionInfo> = sessions.iter().filter(|s| s.current).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].ip.as_ref().unwrap(), "10.0.0.1");
+
+        let id_of = |agent: &str| {
+            let session = sessions
+                .iter()
+                .find(|s| s.user_agent.as_ref().unwrap() == agent);
+            session.unwrap().id.clone()
+        };
+        assert!(!store.revoke(2, &id_of("laptop")).unwrap());
+        assert!(store.revoke(1, &id_of("laptop")).unwrap());
+        assert!(store.get(&laptop, &client("laptop")).is_none());
+
+        assert_eq!(store.revoke_others(&alice).unwrap(), 1);
+        assert!(store.get(&tablet, &client("tablet")).is_none());
+        assert!(store.get(&phone, &client("phone")).is_some());
+        assert!(store.get(&bob, &client("phone")).is_some());
+
+        // logging out
+        store.remove(&phone).unwrap();
+        assert!(store.get(&phone, &client("phone")).is_none());
+        assert!(store.list(&alice).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::cache::{CacheOptions, CacheStats, TtlCache};
+
+/// Failed logins for one username or address since its last success, or since the failures
+/// expired.
+#[derive(Debug, Clone)]
+struct Failures {
+    count: u32,
+    blocked_until: Instant,
+}
+
+/// How many failed logins are allowed before a lockout, and how long the waits are.
+#[derive(Debug, Clone)]
+pub struct ThrottleLimits {
+    pub max_user_failures: u32,
+    pub max_ip_failures: u32,
+    /// The wait after the first failure, which doubles with each failure after that.
+    pub backoff_base: Duration,
+    pub lockout: Duration,
+}
+
+/// Slows down password guessing by counting failed logins per username and per address. Each
+/// failure makes the next attempt wait twice as long, and once there are too many the username
+/// or address is locked out. Attempts are refused before the password is checked, so a locked
+/// out login doesn't cost a password hash either.
+pub struct LoginThrottle {
+    limits: ThrottleLimits,
+    failures: TtlCache<Failures>,
+    // counting a failure is a read followed by a write, so concurrent guesses need to wait
+    // their turn to be counted
+    update: Mutex<()>,
+}
+
+impl LoginThrottle {
+    pub fn new(limits: ThrottleLimits, cache_options: CacheOptions) -> Self {
+        LoginThrottle {
+            // nothing blocks for longer than a lockout, so failures are forgotten once there has
+            // been none for that long
+            failures: TtlCache::with_options(limits.lockout, cache_options),
+            limits,
+            update: Mutex::new(()),
+        }
+    }
+
+    /// Checks whether a login may be attempted, returning how long to wait if not.
+    pub fn check(&self, username: &str, ip: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let wait = keys(username, ip)
+            .iter()
+            .filter_map(|key| self.failures.get(key))
+            .map(|failures| failures.blocked_until.saturating_duration_since(now))
+            .max()
+            .unwrap_or_default();
+
+        if wait > Duration::from_secs(0) {
+            Err(wait)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn record_failure(&self, username: &str, ip: Option<&str>) {
+        let _update = self.update.lock().unwrap();
+        let now = Instant::now();
+        for key in keys(username, ip) {
+            let count = self.failures.get(&key).map_or(0, |f| f.count) + 1;
+            let max = if key.starts_with("ip:") {
+                self.limits.max_ip_failures
+            } else {
+                self.limits.max_user_failures
+            };
+
+            let wait = if count >= max {
+                if count == max {
+                    println!(
+                        "Locked out logins for {} for {}s after {} failed attempts",
+                        key,
+                        self.limits.lockout.as_secs(),
+                        count
+                    );
+                }
+                self.limits.lockout
+            } else {
+                self.backoff(count)
+            };
+
+            self.failures.insert(
+                &key,
+                Failures {
+                    count,
+                    blocked_until: now + wait,
+                },
+            );
+        }
+    }
+
+    /// Forgets the failures for a username once someone logs in as it. Failures from the address
+    /// are kept, since otherwise logging in to one account would reset the count for guesses at
+    /// every other.
+    pub fn record_success(&self, username: &str) {
+        self.failures.remove(&user_key(username));
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.failures.stats()
+    }
+
+    fn backoff(&self, count: u32) -> Duration {
+        let factor = 2u32.saturating_pow(count.saturating_sub(1));
+        self.limits
+            .backoff_base
+            .checked_mul(factor)
+            .map_or(self.limits.lockout, |wait| wait.min(self.limits.lockout))
+    }
+}
+
+fn keys(username: &str, ip: Option<&str>) -> Vec<String> {
+    let mut keys = vec![user_key(username)];
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{}", ip));
+    }
+    keys
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        // long enough that no wait runs out while a test is running
+        let limits = ThrottleLimits {
+            max_user_failures: 3,
+            max_ip_failures: 5,
+            backoff_base: Duration::from_secs(60),
+            lockout: Duration::from_secs(3600),
+        };
+        LoginThrottle::new(limits, CacheOptions::default())
+    }
+
+    /// How long until `username` may try again, rounded up to the minute.
+    fn wait_minutes(throttle: &LoginThrottle, username: &str, ip: Option<&str>) -> u64 {
+        match throttle.check(username, ip) {
+            Ok(()) => 0,
+            Err(wait) => (wait.as_secs() + 59) / 60,
+        }
+    }
+
+    #[test]
+    fn each_failure_doubles_the_wait_until_a_lockout() {
+        let throttle = throttle();
+        let mut waits = Vec::new();
+        for _ in 0..4 {
+            throttle.record_failure("alice", None);
+            waits.push(wait_minutes(&throttle, "Alice", None));
+        }
+        assert_eq!(waits, vec![1, 2, 60, 60]);
+        assert_eq!(wait_minutes(&throttle, "bob", None), 0);
+    }
+
+    #[test]
+    fn an_address_is_locked_out_across_usernames() {
+        let throttle = throttle();
+        for name in &["a", "b", "c", "d"] {
+            throttle.record_failure(name, Some("10.0.0.1"));
+        }
+        assert_eq!(wait_minutes(&throttle, "e", Some("10.0.0.1")), 8);
+        throttle.record_failure("e", Some("10.0.0.1"));
+        assert_eq!(wait_minutes(&throttle, "f", Some("10.0.0.1")), 60);
+        assert_eq!(wait_minutes(&throttle, "f", Some("10.0.0.2")), 0);
+    }
+
+    #[test]
+    fn a_success_clears_the_failures_for_the_username_only() {
+        let throttle = throttle();
+        throttle.record_failure("alice", Some("10.0.0.1"));
+        throttle.record_failure("alice", Some("10.0.0.1"));
+        throttle.record_success("alice");
+        assert_eq!(wait_minutes(&throttle, "alice", None), 0);
+        assert_eq!(wait_minutes(&throttle, "alice", Some("10.0.0.1")), 2);
+
+        // and counting starts over
+        throttle.record_failure("alice", None);
+        assert_eq!(wait_minutes(&throttle, "alice", None), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Time-based one-time passwords as described in RFC 6238, using the defaults that
+//! authenticator apps expect: HMAC-SHA1, six digits and a thirty second step.
+
+use ring::{
+    digest, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const SECRET_LENGTH: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// How many steps either side of the current one are accepted, to allow for clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new shared secret, encoded in base32 the way authenticator apps take it.
+pub fn generate_secret() -> Option<String> {
+    let mut secret = [0u8; SECRET_LENGTH];
+    SystemRandom::new().fill(&mut secret).ok()?;
+    Some(base32_encode(&secret))
+}
+
+/// The `otpauth://` URI that authenticator apps scan as a QR code.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECONDS,
+    )
+}
+
+/// Checks `code` against the codes for the steps around `unix_time`, returning the step it
+/// matched. Callers should refuse a step at or before one that was already used, so that a code
+/// can't be replayed.
+pub fn verify(secret: &str, code: &str, unix_time: i64) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = unix_time / STEP_SECONDS;
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT)
+        .filter(|step| *step >= 0)
+        .map(|step| step as u64)
+        .find(|step| hotp(&key, *step) == code)
+}
+
+/// The code an authenticator shows at `unix_time`.
+#[cfg(test)]
+pub fn code_at(secret: &str, unix_time: i64) -> String {
+    hotp(
+        &base32_decode(secret).unwrap(),
+        (unix_time / STEP_SECONDS) as u64,
+    )
+}
+
+/// A random single-use code for getting in without the authenticator, like `abcde-fghij`.
+pub fn generate_recovery_code() -> Option<String> {
+    let mut bytes = [0u8; 7];
+    SystemRandom::new().fill(&mut bytes).ok()?;
+    let code = base32_encode(&bytes).to_lowercase();
+    Some(format!("{}-{}", &code[..5], &code[5..10]))
+}
+
+/// Recovery codes are compared without their dash or case, since they're often retyped.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The HOTP code (RFC 4226) for one counter value.
+fn hotp(key: &[u8], counter: u64) -> String {
+    let key = hmac::SigningKey::new(&digest::SHA1, key);
+    let signature = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = signature.as_ref();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
@@ -0,0 +1,114 @@
+//! The write-ahead log of changes to notes from when they were only kept in the search index.
+//! Notes are in the note database now, so the log is only read when moving an old index over,
+//! for the changes that never reached a commit.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::search::Note;
+
+/// A change to a single note, with everything needed to make it again.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum NoteChange {
+    Add {
+        user_id: u64,
+        note: Note,
+    },
+    /// Replaces a note, keeping `previous` as revision `revision`.
+    Update {
+        user_id: u64,
+        note: Note,
+        previous: Note,
+        revision: u64,
+    },
+    Trash {
+        user_id: u64,
+        note: Note,
+        deleted_at: DateTime<Utc>,
+    },
+    Restore {
+        user_id: u64,
+        note: Note,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogEntry {
+    /// Goes up by one with every entry. Commits to the old index recorded the last one they had.
+    pub seq: u64,
+    pub change: NoteChange,
+}
+
+/// Reads the entries in a log, if there is one. An entry that was cut short by a crash was never
+/// acknowledged, so it's left out.
+pub fn read_entries(path: &Path) -> io::Result<Vec<LogEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 || !line.ends_with(b"\n") {
+            break;
+        }
+        match serde_json::from_slice(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const ENTRY: &str = r#"{"seq":1,"change":{"op":"add","user_id":1,"note":{"id":0,"title":"A","body":"","created_at":"2020-01-01T00:00:00Z","updated_at":"2020-01-01T00:00:00Z"}}}"#;
+
+    fn read(contents: &str) -> Vec<LogEntry> {
+        let path = std::env::temp_dir().join(format!("soash-test-{}.wal", uuid::Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        let entries = read_entries(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        entries
+    }
+
+    #[test]
+    fn reads_every_complete_entry() {
+        let second = ENTRY.replace(r#""seq":1"#, r#""seq":2"#);
+        let entries = read(&format!("{}\n{}\n", ENTRY, second));
+        assert_eq!(
+            entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn drops_a_torn_last_entry() {
+        // cut off before its newline, even though what's there happens to parse
+        assert_eq!(read(&format!("{}\n{}", ENTRY, ENTRY)).len(), 1);
+        // cut off partway through
+        assert_eq!(read(&format!("{}\n{}", ENTRY, &ENTRY[..40])).len(), 1);
+        // the newline made it to disk but not all of the line before it
+        assert_eq!(read(&format!("{}\n{}\n", ENTRY, &ENTRY[..40])).len(), 1);
+    }
+
+    #[test]
+    fn a_missing_log_has_no_entries() {
+        let path = std::env::temp_dir().join(format!("soash-test-{}.wal", uuid::Uuid::new_v4()));
+        assert!(read_entries(&path).unwrap().is_empty());
+    }
+}